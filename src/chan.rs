@@ -0,0 +1,93 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::heap::Roots;
+use crate::term::TermRef;
+use crate::eval;
+use crate::eval::Task;
+use crate::task;
+
+// A channel is an unbounded queue of terms. Tasks that `recv` on an
+// empty channel are parked here and sent back to the pool by `send`.
+#[derive(Default)]
+pub struct Channel {
+    queue: VecDeque<TermRef>,
+    waiters: VecDeque<Task>,
+}
+
+lazy_static::lazy_static! {
+    static ref CHAN_MAP: Mutex<HashMap<usize,Channel>> =
+                            Mutex::new(HashMap::new());
+}
+static CHAN_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+pub fn new_chan() -> usize {
+    let id = CHAN_COUNT.fetch_add(1, Ordering::SeqCst);
+    let mut map = CHAN_MAP.lock().unwrap();
+    map.insert(id, Channel::default());
+    id
+}
+
+pub fn send(id: usize, value: TermRef) {
+    let waiter = {
+        let mut map = CHAN_MAP.lock().unwrap();
+        let chan = map.get_mut(&id).expect("Channel not found!");
+        chan.queue.push_back(value);
+        chan.waiters.pop_front()
+    };
+    if let Some(task) = waiter {
+        task::send_task(task);
+    }
+}
+
+pub fn recv(id: usize) -> Option<TermRef> {
+    let mut map = CHAN_MAP.lock().unwrap();
+    let chan = map.get_mut(&id).expect("Channel not found!");
+    chan.queue.pop_front()
+}
+
+// What is sent and not received yet, for saving the channel.
+pub fn queue(id: usize) -> Option<Vec<TermRef>> {
+    let map = CHAN_MAP.lock().unwrap();
+    Some(map.get(&id)?.queue.iter().copied().collect())
+}
+
+// Park a task until something is sent on the channel.
+pub fn wait(id: usize, task: Task) {
+    let mut map = CHAN_MAP.lock().unwrap();
+    let chan = map.get_mut(&id).expect("Channel not found!");
+    if chan.queue.is_empty() {
+        chan.waiters.push_back(task);
+    } else {
+        drop(map);
+        task::send_task(task);
+    }
+}
+
+// Take out a parked foreground task, so Ctrl-C can abort it.
+pub fn drop_foreground() -> Option<Task> {
+    let mut map = CHAN_MAP.lock().unwrap();
+    for chan in map.values_mut() {
+        if let Some(i) = chan.waiters.iter().position(|task| task.is_foreground()) {
+            return chan.waiters.remove(i);
+        }
+    }
+    None
+}
+
+pub fn chan_roots(roots: &mut dyn Roots) {
+    let mut map = CHAN_MAP.lock().unwrap();
+    for (id,chan) in map.iter_mut() {
+        for term in &mut chan.queue {
+            *term = roots.root(*term, &format_args!("chan#{}.queue", id));
+        }
+        for task in &mut chan.waiters {
+            eval::task_roots(task, roots);
+        }
+    }
+}
+
+pub fn ids() -> HashSet<usize> {
+    CHAN_MAP.lock().unwrap().keys().copied().collect()
+}
@@ -1,6 +1,170 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
 use crate::term::*;
 use crate::term::Term::*;
 use crate::symbol::*;
+use crate::symbol;
+use crate::strict;
+use crate::lift;
+use crate::gmachine;
+
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum Abstraction {
+    // compile_ski, then Turner's rules in optimize
+    Turner,
+    // linear size, over de Bruijn environments
+    Kiselyov,
+}
+
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum Backend {
+    // bracket abstraction to a tree of fixed combinators
+    Combinators,
+    // lambda lifting, see lift.rs
+    Supercombinators,
+    // supercombinators compiled to code, see gmachine.rs
+    Bytecode,
+}
+
+// What the repl prints of an expression on its way to a task.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum Stage {
+    Parsed,
+    Simplified,
+    Compiled,
+    Optimized,
+    Lifted,
+    Task,
+}
+
+pub const STAGES: [Stage; 6] = [Stage::Parsed, Stage::Simplified,
+    Stage::Compiled, Stage::Optimized, Stage::Lifted, Stage::Task];
+
+lazy_static::lazy_static! {
+    static ref ABSTRACTION: Mutex<Abstraction> =
+                            Mutex::new(Abstraction::Turner);
+    static ref BACKEND: Mutex<Backend> =
+                            Mutex::new(Backend::Combinators);
+    // 0 compiles terms as they are, 1 simplifies them before and
+    // rewrites the combinators after, 2 also evaluates the strict
+    // parameters of definitions on entry
+    static ref OPT_LEVEL: Mutex<usize> = Mutex::new(1);
+    static ref SHOWN: Mutex<Vec<Stage>> = Mutex::new(STAGES.to_vec());
+}
+
+pub fn set_opt_level(level: usize) {
+    *OPT_LEVEL.lock().unwrap() = level;
+}
+
+pub fn opt_level() -> usize {
+    *OPT_LEVEL.lock().unwrap()
+}
+
+pub fn set_shown(stages: Vec<Stage>) {
+    *SHOWN.lock().unwrap() = stages;
+}
+
+pub fn shown(stage: Stage) -> bool {
+    SHOWN.lock().unwrap().contains(&stage)
+}
+
+pub fn shown_stages() -> Vec<Stage> {
+    SHOWN.lock().unwrap().clone()
+}
+
+pub fn set_abstraction(algo: Abstraction) {
+    *ABSTRACTION.lock().unwrap() = algo;
+}
+
+pub fn abstraction() -> Abstraction {
+    *ABSTRACTION.lock().unwrap()
+}
+
+// Bracket abstraction with the selected algorithm.
+pub fn bracket(term: TermRef) -> TermRef {
+    match abstraction() {
+        Abstraction::Turner => compile_ski(term),
+        Abstraction::Kiselyov => compile_kiselyov(term),
+    }
+}
+
+pub fn set_backend(backend: Backend) {
+    *BACKEND.lock().unwrap() = backend;
+}
+
+pub fn backend() -> Backend {
+    *BACKEND.lock().unwrap()
+}
+
+// `name` is only used to name the lifted supercombinators.
+pub fn compile(name: &str, term: TermRef) -> TermRef {
+    match backend() {
+        Backend::Combinators => {
+            let compiled = bracket(term);
+            if opt_level() >= 1 { optimize(compiled) } else { compiled }
+        }
+        Backend::Supercombinators => lift::lift(name, term),
+        Backend::Bytecode => {
+            let root = lift::lift(name, term);
+            for id in lift::reachable(root) {
+                gmachine::compile_super(id);
+            }
+            root
+        }
+    }
+}
+
+// Number of distinct nodes, shared subterms and constants count once.
+pub fn size(term: TermRef) -> usize {
+    let mut seen = HashSet::new();
+    let mut stack = vec![term];
+    while let Some(term) = stack.pop() {
+        if !seen.insert(term.index()) {
+            continue;
+        }
+        match *term {
+            App(t1,t2) => { stack.push(t1); stack.push(t2); }
+            Lam(_,t) => { stack.push(t); }
+            _ => {}
+        }
+    }
+    seen.len()
+}
+
+// Every stage an expression can go through, with its size.
+pub fn explain(term: TermRef) {
+    let row = |stage: &str, term: TermRef| {
+        println!("{:<12}{:>6} nodes  {:?}", stage, size(term), *term);
+    };
+    row("parsed", term);
+    let simplified = symbol::simplify(term);
+    row("simplified", simplified);
+    let sig = symbol::strictness_of(simplified);
+    let strict = strict::make_strict(simplified, &sig);
+    if strict.index() != simplified.index() {
+        row("strict", strict);
+    }
+    let turner = compile_ski(strict);
+    row("turner", turner);
+    row(" optimized", optimize(turner));
+    let kiselyov = compile_kiselyov(strict);
+    row("kiselyov", kiselyov);
+    row(" optimized", optimize(kiselyov));
+    let lifted = lift::lift("explain", strict);
+    let ids = lift::reachable(lifted);
+    let mut nodes = size(lifted);
+    let mut instrs = 0;
+    for id in &ids {
+        let (arity,body) = lift::get(*id).unwrap();
+        nodes += size(body);
+        instrs += gmachine::compile(arity, body).len();
+    }
+    println!("{:<12}{:>6} nodes  {:?}", "lifted", nodes, *lifted);
+    lift::show_supers(lifted);
+    println!("{:<12}{:>6} instructions in {} supercombinators",
+        "bytecode", instrs, ids.len());
+}
 
 pub fn is_free_in(symb: Symb, term: TermRef) -> bool {
     match *term {
@@ -16,6 +180,31 @@ pub fn is_free_in(symb: Symb, term: TermRef) -> bool {
     }
 }
 
+// The free variables of `term` in the order they first appear, the
+// definitions it refers to.
+pub fn free_vars(term: TermRef) -> Vec<Symb> {
+    fn walk(term: TermRef, bound: &mut Vec<Symb>, found: &mut Vec<Symb>) {
+        match *term {
+            Var(x) if !bound.contains(&x) && !found.contains(&x) => {
+                found.push(x);
+            }
+            Lam(x,t) => {
+                bound.push(x);
+                walk(t, bound, found);
+                bound.pop();
+            }
+            App(t1,t2) => {
+                walk(t1, bound, found);
+                walk(t2, bound, found);
+            }
+            _ => {}
+        }
+    }
+    let mut found = Vec::new();
+    walk(term, &mut Vec::new(), &mut found);
+    found
+}
+
 pub fn compile_ski(term: TermRef) -> TermRef {
     match *term {
         Var(_) => { term }
@@ -53,83 +242,355 @@ pub fn compile_ski(term: TermRef) -> TermRef {
     }
 }
 
-// S (K p) I = p
+// Kiselyov, "λ to SKI, semantically". A term is compiled to (g,d),
+// g tells which of the enclosing binders it uses, the innermost first,
+// and d takes the values of the used ones as arguments, the outermost
+// first. Applying two of them walks their binders from the inside,
+// passing a run of k binders to both sides, the left or the right one
+// with the bulk combinators:
+// Sk f g x1..xk = f x1..xk (g x1..xk)
+// Ck f g x1..xk = f x1..xk g
+// Bk f g x1..xk = f (g x1..xk)
+// they are built from S, C and B once per k and shared.
+struct Kiselyov {
+    scope: Vec<Symb>,
+    bulk: HashMap<(u32,usize),TermRef>,
+}
+
+impl Kiselyov {
+    fn new() -> Kiselyov {
+        Kiselyov { scope: Vec::new(), bulk: HashMap::new() }
+    }
+    fn bulk(&mut self, comb: TermRef, k: usize) -> TermRef {
+        if k == 1 {
+            return comb;
+        }
+        if let Some(term) = self.bulk.get(&(comb.index(),k)) {
+            return *term;
+        }
+        let prev = self.bulk(comb, k - 1);
+        let term = match *comb {
+            // S(k+1) = B S (B Sk)
+            S => app!(C_B,C_S,app!(C_B,prev)),
+            // C(k+1) = B C (B Ck)
+            C => app!(C_B,C_C,app!(C_B,prev)),
+            // B(k+1) = B B Bk
+            B => app!(C_B,C_B,prev),
+            _ => { panic!("no bulk version of {:?}!", *comb); }
+        };
+        self.bulk.insert((comb.index(),k), term);
+        term
+    }
+    // d1 d2 under the binders both of them use
+    fn apply(&mut self, g1: &[bool], d1: TermRef,
+             g2: &[bool], d2: TermRef) -> TermRef {
+        let used = |g: &[bool], i: usize| i < g.len() && g[i];
+        let len = g1.len().max(g2.len());
+        // skip the binders none of them uses
+        let mut i = 0;
+        while i < len && !used(g1,i) && !used(g2,i) {
+            i += 1;
+        }
+        if i == len {
+            return app!(d1,d2);
+        }
+        let (g1,g2) = (&g1[i.min(g1.len())..], &g2[i.min(g2.len())..]);
+        // \x. d1 x = d1
+        if !used(g1,0) && g2.iter().filter(|u| **u).count() == 1
+            && g2[0] && matches!(*d2, I) {
+            return d1;
+        }
+        let head = (used(g1,0), used(g2,0));
+        let mut k = 1;
+        while k < len - i && (used(g1,k), used(g2,k)) == head {
+            k += 1;
+        }
+        let comb = match head {
+            (true,true) => C_S,
+            (true,false) => C_C,
+            _ => C_B,
+        };
+        let comb = self.bulk(comb, k);
+        let (g1,g2) = (&g1[k.min(g1.len())..], &g2[k.min(g2.len())..]);
+        let d1 = self.apply(&[], comb, g1, d1);
+        self.apply(g1, d1, g2, d2)
+    }
+    fn convert(&mut self, term: TermRef) -> (Vec<bool>,TermRef) {
+        match *term {
+            Var(x) => {
+                if let Some(i) = self.scope.iter().rev().position(|y| *y == x) {
+                    let mut g = vec![false; i + 1];
+                    g[i] = true;
+                    (g,C_I)
+                } else {
+                    // a definition in the dictionary
+                    (Vec::new(),term)
+                }
+            }
+            Lam(x,t) => {
+                self.scope.push(x);
+                let (g,d) = self.convert(t);
+                self.scope.pop();
+                match g.first() {
+                    None => (g,app!(C_K,d)),
+                    Some(true) => (g[1..].to_vec(),d),
+                    Some(false) => {
+                        let d = self.apply(&[], C_K, &g[1..], d);
+                        (g[1..].to_vec(),d)
+                    }
+                }
+            }
+            App(t1,t2) => {
+                let (g1,d1) = self.convert(t1);
+                let (g2,d2) = self.convert(t2);
+                let d = self.apply(&g1, d1, &g2, d2);
+                let len = g1.len().max(g2.len());
+                let g = (0..len).map(|i| {
+                    g1.get(i) == Some(&true) || g2.get(i) == Some(&true)
+                }).collect();
+                (g,d)
+            }
+            _ => { (Vec::new(),term) }
+        }
+    }
+}
+
+pub fn compile_kiselyov(term: TermRef) -> TermRef {
+    let (g,d) = Kiselyov::new().convert(term);
+    assert!(g.is_empty(), "free de Bruijn index left!");
+    d
+}
+
+// (f x) => (f,x)
+fn unapp(term: TermRef) -> Option<(TermRef,TermRef)> {
+    if let App(t1,t2) = *term { Some((t1,t2)) } else { None }
+}
+
+// (K p) => p
+fn k_arg(term: TermRef) -> Option<TermRef> {
+    let (t1,p) = unapp(term)?;
+    if let K = *t1 { Some(p) } else { None }
+}
+
+// (B p q) => (p,q)
+fn b_args(term: TermRef) -> Option<(TermRef,TermRef)> {
+    let (t1,q) = unapp(term)?;
+    let (t11,p) = unapp(t1)?;
+    if let B = *t11 { Some((p,q)) } else { None }
+}
+
+// Turner's rules, tried in order on a term in form of (S arg1 arg2):
 // S (K p) (K q) = K (p q)
+// S (K p) I = p
 // S (K p) (B q r) = B* p q r
 // S (K p) q = B p q
 // S (B p q) (K r) = C' p q r
 // S (B p q) r = S' p q r
 // S p (K q) = C p q
+// every rule makes the term smaller, so rewriting always stops.
+fn rewrite(term: TermRef) -> Option<TermRef> {
+    let (t1,arg2) = unapp(term)?;
+    let (t11,arg1) = unapp(t1)?;
+    if let S = *t11 {} else { return None; }
+    if let Some(p) = k_arg(arg1) {
+        if let Some(q) = k_arg(arg2) {
+            return Some(app!(C_K,app!(p,q)));
+        }
+        if let I = *arg2 {
+            return Some(p);
+        }
+        if let Some((q,r)) = b_args(arg2) {
+            return Some(app!(C_BS,p,q,r));
+        }
+        return Some(app!(C_B,p,arg2));
+    }
+    if let Some((p,q)) = b_args(arg1) {
+        if let Some(r) = k_arg(arg2) {
+            return Some(app!(C_CP,p,q,r));
+        }
+        return Some(app!(C_SP,p,q,arg2));
+    }
+    if let Some(q) = k_arg(arg2) {
+        return Some(app!(C_C,arg1,q));
+    }
+    None
+}
+
+// One bottom-up pass over the whole term, and whether it rewrote
+// anything. A rewrite builds new applications, e.g. (p q) in K (p q),
+// which may match a rule again on the next pass.
+fn optimize_pass(term: TermRef) -> (TermRef,bool) {
+    let (term,changed) = match *term {
+        App(t1,t2) => {
+            let (u1,c1) = optimize_pass(t1);
+            let (u2,c2) = optimize_pass(t2);
+            if c1 || c2 { (app!(u1,u2),true) } else { (term,false) }
+        }
+        Lam(x,t) => {
+            let (u,c) = optimize_pass(t);
+            if c { (lam!(x,u),true) } else { (term,false) }
+        }
+        _ => { (term,false) }
+    };
+    match rewrite(term) {
+        Some(new) => (new,true),
+        None => (term,changed),
+    }
+}
+
 pub fn optimize(term: TermRef) -> TermRef {
-    if let App(t1,t2) = *term {
-        if let App(t11,t12) = *t1 {
-            if let S = *t11 {
-                let arg1 = t12;
-                let arg2 = t2;
-                // term in form of (S arg1 arg2)
-                if let App(t1,t2) = *arg1 {
-                    if let K = *t1 {
-                        let p = t2;
-                        // term in form of (S (K p) arg2)
-                        if let I = *arg2 {
-                             // S (K p) I = p
-                            return p;
-                        }
-                        if let App(t1,t2) = *arg2 {
-                            if let K = *t1 {
-                                let q = t2;
-                                // S (K p) (K q) = K (p q)
-                                return app!(C_K,app!(p,q));
-                            }
-                            if let App(t1,t2) = *t1 {
-                                if let B = *t1 {
-                                    let q = t2;
-                                    // S (K p) (B q r) = B* p q r
-                                    return app!(C_BS,app!(p,q));
-                                }
-                            }
-                        }
-                        let q = arg2;
-                        // S (K p) q = B p q
-                        return app!(C_B,p,q);
-                    }
-                    if let App(t11,t12) = *t1 {
-                        if let B = *t11 {
-                            let p = t12;
-                            let q = t2;
-                            // term in form of (S (B p q) arg2)
-                            if let App(t1,t2) = *arg2 {
-                                if let K = *t1 {
-                                    let r = t2;
-                                    // S (B p q) (K r) = C' p q r
-                                    return app!(C_CP,p,q,r);
-                                }
-                                let r = arg2;
-                                // S (B p q) r = S' p q r
-                                return app!(C_SP,p,q,r);
-                            }
-                        }
-                    }
-                }
-                let p = arg1;
-                if let App(t1,t2) = *arg2 {
-                    if let K = *t1 {
-                        let q = t2;
-                        // S p (K q) = C p q
-                        return app!(C_C,p,q);
-                    }
-                }
-                let q = arg2;
-                // S p q = S T[p] T[q]
-                return app!(C_S,optimize(p),optimize(q));
+    let mut term = term;
+    loop {
+        let (new,changed) = optimize_pass(term);
+        if !changed {
+            return new;
+        }
+        term = new;
+    }
+}
 
-            } else {
-                return app!(optimize(t1),optimize(t2));
+#[cfg(test)]
+fn reduce(term: TermRef) -> String {
+    let mut task = crate::eval::Task::new(term);
+    let ret = task.eval(1 << 20).expect("term didn't reduce!");
+    format!("{:?}", *ret)
+}
+
+#[test]
+pub fn turner_rules_test() {
+    let add = || app!(C_E2,C_ADDI);
+    let inc = || app!(C_E2,C_ADDI,i!(1));
+    let dbl = || app!(C_E2,C_MULI,i!(2));
+    let neg = || app!(C_E2,C_SUBI,i!(10));
+    let k = |t| app!(C_K,t);
+    let b = |p,q| app!(C_B,p,q);
+    // (S arg1 arg2, what it should turn into, how many 5s to apply)
+    let rules = vec![
+        (app!(C_S,k(inc()),k(i!(2))), "(K (E2 AddI 1 2))", 1),
+        (app!(C_S,k(inc()),C_I), "(E2 AddI 1)", 1),
+        (app!(C_S,k(inc()),b(dbl(),neg())),
+            "(B* (E2 AddI 1) (E2 MulI 2) (E2 SubI 10))", 1),
+        (app!(C_S,k(inc()),dbl()), "(B (E2 AddI 1) (E2 MulI 2))", 1),
+        (app!(C_S,b(add(),dbl()),k(i!(3))),
+            "(C' (E2 AddI) (E2 MulI 2) 3)", 1),
+        (app!(C_S,b(add(),dbl()),neg()),
+            "(S' (E2 AddI) (E2 MulI 2) (E2 SubI 10))", 1),
+        (app!(C_S,add(),k(i!(3))), "(C (E2 AddI) 3)", 1),
+        // K (S (K p) I) has to be rewritten again
+        (app!(C_S,k(app!(C_S,k(inc()))),k(C_I)), "(K (E2 AddI 1))", 2),
+    ];
+    for (lhs,rhs,n) in rules {
+        let opt = optimize(lhs);
+        assert_eq!(format!("{:?}", *opt), rhs);
+        let (mut t1, mut t2) = (lhs, opt);
+        for _ in 0..n {
+            t1 = app!(t1,i!(5));
+            t2 = app!(t2,i!(5));
+        }
+        assert_eq!(reduce(t1), reduce(t2));
+    }
+}
+
+#[test]
+pub fn optimize_programs_test() {
+    let programs = vec![
+        ("\\x.\\y. + x y", vec![3,4]),
+        ("\\x.\\y.\\z. + (* x y) (- z x)", vec![2,3,4]),
+        ("\\f.\\x. f (f x)", vec![]),
+        ("\\x. (\\y. * y y) (+ x 1)", vec![6]),
+        ("\\x.\\y. if (> x y) (- x y) (- y x)", vec![3,8]),
+        ("\\a.\\b.\\c. (\\d. + (* a d) (* b c)) (+ a c)", vec![1,2,3]),
+    ];
+    for (text,args) in programs {
+        let ski = compile_ski(crate::parser::parse_term(text).unwrap());
+        let opt = optimize(ski);
+        assert!(format!("{:?}", *opt).len() <= format!("{:?}", *ski).len());
+        let (mut t1, mut t2) = (ski, opt);
+        if args.is_empty() {
+            // apply the higher order ones to a function first
+            t1 = app!(t1,app!(C_E2,C_MULI,i!(3)),i!(2));
+            t2 = app!(t2,app!(C_E2,C_MULI,i!(3)),i!(2));
+        }
+        for n in args {
+            t1 = app!(t1,i!(n));
+            t2 = app!(t2,i!(n));
+        }
+        assert_eq!(reduce(t1), reduce(t2));
+    }
+}
+
+#[cfg(test)]
+pub fn reduce_steps(term: TermRef) -> (String,usize) {
+    let mut task = crate::eval::Task::new(term);
+    let mut steps = 1;
+    loop {
+        if let Some(ret) = task.eval(1) {
+            return (format!("{:?}", *ret), steps);
+        }
+        steps += 1;
+    }
+}
+
+// The programs of the repo's .nrm files, every definition that isn't
+// a function, closed over the definitions it uses. Recursion goes
+// through a fixpoint combinator, the dictionary isn't involved.
+#[cfg(test)]
+pub fn test_programs() -> Vec<(String,TermRef)> {
+    use crate::parser::{self, Command};
+    let mut defs = HashMap::new();
+    let mut order = Vec::new();
+    for path in ["test.nrm", "programs.nrm"] {
+        let text = std::fs::read_to_string(path).unwrap();
+        for command in text.split(";;") {
+            let mut par = parser::Parser::new(command.trim().to_string());
+            if let Some(Command::Define(symb,input)) = parser::read_command(&mut par) {
+                defs.insert(symb, parser::parse_term(&input).unwrap());
+                order.push(symb);
+            }
+        }
+    }
+    let fix = parser::parse_term("(\\f. (\\x. f (x x)) (\\x. f (x x)))").unwrap();
+    fn close(symb: Symb, defs: &HashMap<Symb,TermRef>, fix: TermRef) -> TermRef {
+        let body = defs[&symb];
+        let used = free_vars(body);
+        let mut term = if used.contains(&symb) {
+            app!(fix,lam!(symb,body))
+        } else { body };
+        for x in used {
+            if x != symb && defs.contains_key(&x) {
+                term = app!(lam!(x,term),close(x, defs, fix));
             }
-        } else {
-            return app!(t1,optimize(t2));
         }
-    } else {
-        return term;
+        term
     }
-}
\ No newline at end of file
+    order.into_iter()
+        .filter(|symb| !matches!(*defs[symb], Lam(_,_)))
+        .map(|symb| (symb.str(), close(symb, &defs, fix)))
+        .collect()
+}
+
+#[test]
+pub fn bracket_compare_test() {
+    let programs = test_programs();
+    assert!(programs.len() >= 8);
+    // a few small terms come out bigger or slower, by less than a
+    // quarter, the nested binders win overall
+    let within = |a: usize, b: usize| a * 4 <= b * 5;
+    let (mut nodes1, mut nodes2, mut steps1, mut steps2) = (0, 0, 0, 0);
+    for (name,term) in programs {
+        let turner = optimize(compile_ski(term));
+        let kiselyov = compile_kiselyov(term);
+        let (ret1,n1) = reduce_steps(turner);
+        let (ret2,n2) = reduce_steps(kiselyov);
+        assert_eq!(ret1, ret2, "{}", name);
+        let (size1,size2) = (size(turner),size(kiselyov));
+        assert!(within(size2, size1), "{}: {} nodes against {}", name, size2, size1);
+        assert!(within(n2, n1), "{}: {} steps against {}", name, n2, n1);
+        nodes1 += size1;
+        nodes2 += size2;
+        steps1 += n1;
+        steps2 += n2;
+    }
+    assert!(nodes2 < nodes1);
+    assert!(within(steps2, steps1));
+}
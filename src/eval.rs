@@ -1,19 +1,57 @@
-use crate::term;
 use crate::term::{ TermRef };
+use crate::heap;
+use crate::heap::Roots;
 use crate::term::Term::*;
 use crate::symbol;
 use crate::compile;
+use crate::lift;
+use crate::gmachine;
+use crate::gmachine::{Instr, Code};
+use crate::task;
+use crate::task::Priority;
+use crate::spark;
+use crate::spark::Demand;
+use crate::chan;
+use crate::verify;
+use crate::image::{Reader, Writer};
 
 use std::fmt;
 use std::fmt::Debug;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum TaskKind {
+    Foreground,
+    Background,
+    Spark,
+}
+
+// what a parked task is waiting for
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum Block {
+    Spark(usize),
+    Chan(usize),
+}
+
+static TASK_COUNT: AtomicUsize = AtomicUsize::new(0);
 
 #[derive(PartialEq,Eq)]
 pub struct Task {
+    id: usize,
     stack: Vec<TermRef>,
     with: TermRef,
     frame: Vec<usize>,
     len: usize,
     ret: Option<TermRef>,
+    // the code every `Code` node on the stack goes back to, the
+    // innermost last
+    codes: Vec<Code>,
+    kind: TaskKind,
+    priority: Priority,
+    blocked: Option<Block>,
+    // why the task can't go on, it is failed by the worker
+    error: Option<String>,
+    steps: usize,
 }
 
 
@@ -39,26 +77,137 @@ impl Debug for Task {
     }
 }
 
-pub fn task_copy(task: &mut Task) {
-    task.with = term::term_copy(task.with);
-    for ptr in &mut task.stack {
-        *ptr = term::term_copy(*ptr)
+pub fn task_roots(task: &mut Task, roots: &mut dyn Roots) {
+    task.with = roots.root(task.with, &"task.with");
+    for (i, ptr) in task.stack.iter_mut().enumerate() {
+        *ptr = roots.root(*ptr, &format_args!("task.stack[{}]", i));
+    }
+    if let Some(ret) = task.ret {
+        task.ret = Some(roots.root(ret, &"task.ret"));
+    }
+    for (i, code) in task.codes.iter_mut().enumerate() {
+        *code = gmachine::root_code(code, roots, &format_args!("task.codes[{}]", i));
+    }
+}
+
+pub fn task_save(task: &Task, writer: &mut Writer) -> Result<(),String> {
+    writer.term(task.with)?;
+    writer.u32(task.stack.len() as u32);
+    for term in &task.stack {
+        writer.term(*term)?;
     }
+    writer.u32(task.frame.len() as u32);
+    for len in &task.frame {
+        writer.u32(*len as u32);
+    }
+    writer.u32(task.len as u32);
     if let Some(ret) = task.ret {
-        task.ret = Some(term::term_copy(ret));
+        writer.u8(1);
+        writer.term(ret)?;
+    } else {
+        writer.u8(0);
+    }
+    writer.u32(task.codes.len() as u32);
+    for code in &task.codes {
+        gmachine::code_save(code, writer)?;
     }
+    Ok(())
+}
+
+// A loaded task runs in the background with a fresh id.
+pub fn task_load(reader: &mut Reader) -> Result<Task,String> {
+    let mut task = Task::new(reader.term()?);
+    for _ in 0..reader.u32()? {
+        task.stack.push(reader.term()?);
+    }
+    for _ in 0..reader.u32()? {
+        task.frame.push(reader.u32()? as usize);
+    }
+    task.len = reader.u32()? as usize;
+    if reader.u8()? != 0 {
+        task.ret = Some(reader.term()?);
+    }
+    for _ in 0..reader.u32()? {
+        task.codes.push(gmachine::code_load(reader)?);
+    }
+    // every frame holds its arguments, the caller and the node it was
+    // called on
+    if task.len + task.frame.iter().sum::<usize>() != task.stack.len() {
+        return Err("Bad task in image!".to_string());
+    }
+    // and every Code node, from the outermost one, goes back into the
+    // code saved for it
+    let pcs: Vec<usize> = task.stack.iter().chain([&task.with])
+        .filter_map(|term| if let Code(pc) = **term { Some(pc as usize) } else { None })
+        .collect();
+    if pcs.len() != task.codes.len()
+        || pcs.iter().zip(&task.codes).any(|(pc,code)| *pc >= code.len()) {
+        return Err("Bad task in image!".to_string());
+    }
+    Ok(task)
 }
 
 impl Task {
     pub fn new(term: TermRef) -> Task {
         Task {
+            id: TASK_COUNT.fetch_add(1, Ordering::SeqCst),
             stack: Vec::new(),
             with: term,
             frame: Vec::new(),
             len: 0,
             ret: None,
+            codes: Vec::new(),
+            kind: TaskKind::Background,
+            priority: Priority::Batch,
+            blocked: None,
+            error: None,
+            steps: 0,
         }
     }
+    pub fn id(&self) -> usize {
+        self.id
+    }
+    pub fn kind(&self) -> TaskKind {
+        self.kind
+    }
+    pub fn is_foreground(&self) -> bool {
+        self.kind == TaskKind::Foreground
+    }
+    pub fn set_foreground(&mut self) {
+        self.kind = TaskKind::Foreground;
+        self.priority = Priority::Interactive;
+    }
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+    pub fn take_blocked(&mut self) -> Option<Block> {
+        self.blocked.take()
+    }
+    pub fn take_error(&mut self) -> Option<String> {
+        self.error.take()
+    }
+    fn spark(&mut self, term: TermRef) {
+        let mut term = term;
+        // (I x) is a fresh node made by S, spark the shared x instead
+        while let App(t1,t2) = *term {
+            if !matches!(*t1, I) {
+                break;
+            }
+            term = t2;
+        }
+        // never a link cell or a perm node of a definition (consed ones
+        // included), they are shared by every caller and would be
+        // overwritten
+        if !matches!(*term, App(_,_)) || heap::is_perm(term) {
+            return;
+        }
+        let id = spark::new_spark(term, alloc!(*term));
+        heap::term_update(term, Spark(id));
+        let mut task = Task::new(term);
+        task.kind = TaskKind::Spark;
+        task.priority = self.priority;
+        task::send_task(task);
+    }
     fn push(&mut self, term: TermRef) {
         self.len += 1;
         self.stack.push(term);
@@ -73,7 +222,8 @@ impl Task {
     }
     fn call(&mut self, term: TermRef) {
         self.stack.push(self.with);
-        self.frame.push(self.len + 1);
+        self.stack.push(term);
+        self.frame.push(self.len + 2);
         self.with = term;
         self.len = 0;
     }
@@ -84,8 +234,10 @@ impl Task {
         }
         assert!(self.ret.is_none());
         self.ret = Some(term);
+        // the node it was called on
+        self.stack.pop();
         self.with = self.stack.pop().unwrap();
-        self.len = self.frame.pop().unwrap() - 1;
+        self.len = self.frame.pop().unwrap() - 2;
     }
     fn eager(&mut self, index: u8) {
         let n = index as usize;
@@ -106,6 +258,90 @@ impl Task {
             }
         }
     }
+    // Run the code of a supercombinator from `pc` until it unwinds a
+    // result or has to evaluate something. Then its entries stay on the
+    // frame and it is called, with a `Code` node to come back to.
+    fn run_code(&mut self, code: &Code, pc: usize) {
+        macro_rules! int_op {
+            ($op:tt, $wrap:ident) => {{
+                let (x,y) = (self.pop(),self.pop());
+                if let (DInt(a),DInt(b)) = (*x,*y) {
+                    self.push($wrap!(a $op b));
+                } else {
+                    panic!("{:?} takes two interger!",code[pc]);
+                }
+            }};
+        }
+        let mut pc = pc;
+        loop {
+            match code[pc] {
+                Instr::Push(k) => {
+                    let term = self.stack[self.stack.len() - 1 - k];
+                    self.push(term);
+                }
+                Instr::PushInt(n) => { self.push(i!(n)); }
+                Instr::PushGlobal(term) => { self.push(term); }
+                Instr::MkAp => {
+                    let f = self.pop();
+                    let x = self.pop();
+                    self.push(app!(f,x));
+                }
+                Instr::Eval => {
+                    let term = self.pop();
+                    match *term {
+                        DInt(_) | DBool(_) | DChar(_) | DReal(_) | Chan(_) => {
+                            self.push(term);
+                        }
+                        _ => {
+                            self.codes.push(code.clone());
+                            self.with = alloc!(Code(pc as u32 + 1));
+                            self.call(term);
+                            return;
+                        }
+                    }
+                }
+                Instr::Add => int_op!(+, i),
+                Instr::Sub => int_op!(-, i),
+                Instr::Mul => int_op!(*, i),
+                Instr::Div => int_op!(/, i),
+                Instr::Gt => int_op!(>, b),
+                Instr::Lt => int_op!(<, b),
+                Instr::Eq => int_op!(==, b),
+                Instr::Jump(l) => { pc = l; continue; }
+                Instr::JumpFalse(l) => {
+                    if let DBool(p) = *self.pop() {
+                        if !p { pc = l; continue; }
+                    } else {
+                        panic!("{:?} takes a boolean!",code[pc]);
+                    }
+                }
+                Instr::Update(n) => {
+                    // only when the frame is this redex, then the node
+                    // it was called on is right under the arguments
+                    let m = self.stack.len();
+                    if self.len == n + 1 && !self.frame.is_empty() {
+                        let (root,result) = (self.stack[m - n - 2], self.stack[m - 1]);
+                        if matches!(*root, App(_,_)) && !heap::is_perm(root)
+                            && root.index() != result.index() {
+                            heap::term_update(root, *result);
+                        }
+                    }
+                }
+                Instr::Pop(n) => {
+                    let term = self.pop();
+                    for _ in 0..n {
+                        self.pop();
+                    }
+                    self.push(term);
+                }
+                Instr::Unwind => {
+                    self.with = self.pop();
+                    return;
+                }
+            }
+            pc += 1;
+        }
+    }
     pub fn eval(&mut self, timeslice: i32) -> Option<TermRef> {
         macro_rules! rewind_check {
             ($n: expr) => {
@@ -140,13 +376,28 @@ impl Task {
             };
         }
         assert!(timeslice > 0);
+        let verify_steps = verify::step_interval();
         for _ in 0..timeslice {
+            if let Some(n) = verify_steps {
+                self.steps += 1;
+                if self.steps >= n {
+                    self.steps = 0;
+                    verify::verify_task(self);
+                }
+            }
             //println!("eval: {:?}",self);
             match *self.with {
                 Var(x) => {
-                    self.with = symbol::lookup(x).expect(
-                        &format!("Definition {:?} not found!",x)[..]
-                    );
+                    // deleted while something still used it
+                    if let Some(term) = symbol::lookup(x) {
+                        self.with = term;
+                    } else {
+                        self.error = Some(format!("{:?} is not defined!", x));
+                        return None;
+                    }
+                }
+                Link(_,t) => {
+                    self.with = t;
                 }
                 Lam(_,_) => {
                     self.with = compile::compile_ski(self.with);
@@ -188,8 +439,7 @@ impl Task {
                 }
                 Bs => {
                     reserve!(c,f,g,x);
-                    self.push(app!(g,x));
-                    self.push(f);
+                    self.push(app!(f,app!(g,x)));
                     self.with = c;
                 }
                 Cp => {
@@ -198,6 +448,27 @@ impl Task {
                     self.push(app!(f,x));
                     self.with = c;
                 }
+                Super(id) => {
+                    let (arity,body,code) = lift::entry(id).unwrap_or_else(||
+                        panic!("Supercombinator #{} not found!",id)
+                    );
+                    rewind_check!(arity);
+                    if let Some(code) = code {
+                        self.run_code(&code, 0);
+                        continue;
+                    }
+                    // the first argument is on top
+                    let m = self.stack.len() - arity;
+                    self.with = lift::instantiate(body, &self.stack[m..]);
+                    self.stack.truncate(m);
+                    self.len -= arity;
+                }
+                Code(pc) => {
+                    let code = self.codes.pop().unwrap();
+                    let value = self.ret.take().unwrap();
+                    self.push(value);
+                    self.run_code(&code, pc as usize);
+                }
                 E1 => {
                     self.eager(1);
                 }
@@ -213,6 +484,67 @@ impl Task {
                 E(n) => {
                     self.eager(n);
                 }
+                Seq => {
+                    reserve!(_x,y);
+                    self.with = y;
+                }
+                Par => {
+                    reserve!(x,y);
+                    self.spark(x);
+                    self.with = y;
+                }
+                Spark(id) => {
+                    match spark::demand(id, self.id) {
+                        Demand::Done => {}
+                        Demand::Claim(term) => {
+                            self.with = app!(eager!(1),alloc!(Fulfil(id)),term);
+                        }
+                        Demand::Wait => {
+                            if self.kind == TaskKind::Spark
+                                && self.frame.is_empty() {
+                                // someone else took over this spark
+                                return Some(self.with);
+                            }
+                            self.blocked = Some(Block::Spark(id));
+                            return None;
+                        }
+                    }
+                }
+                Fulfil(id) => {
+                    reserve!(x);
+                    spark::fulfil(id, x);
+                    self.with = x;
+                }
+                NewChan => {
+                    reserve!(f);
+                    let id = chan::new_chan();
+                    self.with = app!(f,alloc!(Chan(id)));
+                }
+                SendChan => {
+                    reserve!(c,x,k);
+                    if let Chan(id) = *c {
+                        chan::send(id, x);
+                        self.with = k;
+                    } else {
+                        panic!("{:?} takes a channel and two terms!",self.with);
+                    }
+                }
+                RecvChan => {
+                    reserve!(c,f);
+                    if let Chan(id) = *c {
+                        if let Some(x) = chan::recv(id) {
+                            self.with = app!(f,x);
+                        } else {
+                            // park until someone sends
+                            self.push(f);
+                            self.push(c);
+                            self.blocked = Some(Block::Chan(id));
+                            return None;
+                        }
+                    } else {
+                        panic!("{:?} takes a channel and a term!",self.with);
+                    }
+                }
                 AddI => {
                     reserve!(x,y);
                     if let (DInt(a),DInt(b)) = (*x,*y) {
@@ -290,7 +622,7 @@ impl Task {
                     }
                 }
                 
-                DInt(_) | DBool(_) => {
+                DInt(_) | DBool(_) | Chan(_) => {
                     assert_eq!(self.len, 0);
                     if self.frame.is_empty() {
                         // task finished
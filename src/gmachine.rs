@@ -0,0 +1,300 @@
+use std::fmt;
+use std::fmt::Debug;
+use std::sync::Arc;
+
+use crate::term::*;
+use crate::term::Term::*;
+use crate::lift;
+use crate::heap::Roots;
+use crate::image::{Reader, Writer};
+
+// G-machine style code for a supercombinator. It runs on the frame of
+// the task that unwound the supercombinator, the arguments are on top
+// with the first one last pushed, and `Push(k)` copies the k-th entry
+// counted from the top. The node the frame was called on sits under
+// it, a body ends with `Update(n)` writing the result over that node
+// when the frame is just this redex, so the work is shared.
+#[derive(Clone,Copy,PartialEq,Eq)]
+pub enum Instr {
+    Push(usize),
+    PushInt(i64),
+    PushGlobal(TermRef),
+    MkAp,
+    // pop, evaluate, push back the value
+    Eval,
+    Add,Sub,Mul,Div,
+    Gt,Lt,Eq,
+    Jump(usize),
+    JumpFalse(usize),
+    // overwrite the redex under the top and its n arguments with the top
+    Update(usize),
+    // keep the top, drop the n arguments under it
+    Pop(usize),
+    // go on reducing the top
+    Unwind,
+}
+
+// shared with the tasks running it
+pub type Code = Arc<Vec<Instr>>;
+
+impl Debug for Instr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Instr::Push(k) => write!(f,"PUSH {}",k),
+            Instr::PushInt(n) => write!(f,"PUSHINT {}",n),
+            Instr::PushGlobal(t) => write!(f,"PUSHGLOBAL {:?}",**t),
+            Instr::MkAp => write!(f,"MKAP"),
+            Instr::Eval => write!(f,"EVAL"),
+            Instr::Add => write!(f,"ADD"),
+            Instr::Sub => write!(f,"SUB"),
+            Instr::Mul => write!(f,"MUL"),
+            Instr::Div => write!(f,"DIV"),
+            Instr::Gt => write!(f,"GT"),
+            Instr::Lt => write!(f,"LT"),
+            Instr::Eq => write!(f,"EQ"),
+            Instr::Jump(l) => write!(f,"JUMP {}",l),
+            Instr::JumpFalse(l) => write!(f,"JFALSE {}",l),
+            Instr::Update(n) => write!(f,"UPDATE {}",n),
+            Instr::Pop(n) => write!(f,"POP {}",n),
+            Instr::Unwind => write!(f,"UNWIND"),
+        }
+    }
+}
+
+// E2 op a b, with op an integer primitive
+fn binop(term: TermRef) -> Option<(Instr,TermRef,TermRef)> {
+    if let App(t1,b) = *term {
+        if let App(t11,a) = *t1 {
+            if let App(e,op) = *t11 {
+                if let E2 = *e {
+                    let instr = match *op {
+                        AddI => Instr::Add,
+                        SubI => Instr::Sub,
+                        MulI => Instr::Mul,
+                        DivI => Instr::Div,
+                        GrtI => Instr::Gt,
+                        LssI => Instr::Lt,
+                        EqlI => Instr::Eq,
+                        _ => { return None; }
+                    };
+                    return Some((instr,a,b));
+                }
+            }
+        }
+    }
+    None
+}
+
+// E1 Ifte c t e
+fn ifte(term: TermRef) -> Option<(TermRef,TermRef,TermRef)> {
+    if let App(t1,e) = *term {
+        if let App(t11,t) = *t1 {
+            if let App(t111,c) = *t11 {
+                if let App(e1,op) = *t111 {
+                    if let (E1,Ifte) = (*e1,*op) {
+                        return Some((c,t,e));
+                    }
+                }
+            }
+        }
+    }
+    None
+}
+
+struct Compiler {
+    code: Vec<Instr>,
+}
+
+impl Compiler {
+    fn emit(&mut self, instr: Instr) -> usize {
+        self.code.push(instr);
+        self.code.len() - 1
+    }
+    fn patch(&mut self, at: usize) {
+        let here = self.code.len();
+        match &mut self.code[at] {
+            Instr::Jump(l) | Instr::JumpFalse(l) => { *l = here; }
+            _ => { unreachable!(); }
+        }
+    }
+    // build the graph of `term`, `depth` entries were pushed since
+    // the arguments
+    fn construct(&mut self, term: TermRef, depth: usize) {
+        match *term {
+            Arg(i) => { self.emit(Instr::Push(i + depth)); }
+            DInt(n) => { self.emit(Instr::PushInt(n)); }
+            App(t1,t2) => {
+                self.construct(t2, depth);
+                self.construct(t1, depth + 1);
+                self.emit(Instr::MkAp);
+            }
+            _ => { self.emit(Instr::PushGlobal(term)); }
+        }
+    }
+    // push the value of `term`
+    fn strict(&mut self, term: TermRef, depth: usize) {
+        if let Some((instr,a,b)) = binop(term) {
+            self.strict(b, depth);
+            self.strict(a, depth + 1);
+            self.emit(instr);
+        } else if let Some((c,t,e)) = ifte(term) {
+            self.strict(c, depth);
+            let jump_false = self.emit(Instr::JumpFalse(0));
+            self.strict(t, depth);
+            let jump = self.emit(Instr::Jump(0));
+            self.patch(jump_false);
+            self.strict(e, depth);
+            self.patch(jump);
+        } else if let DInt(n) = *term {
+            self.emit(Instr::PushInt(n));
+        } else {
+            self.construct(term, depth);
+            self.emit(Instr::Eval);
+        }
+    }
+    // the body of a supercombinator taking `arity` arguments
+    fn body(&mut self, term: TermRef, arity: usize) {
+        if let Some((c,t,e)) = ifte(term) {
+            self.strict(c, 0);
+            let jump_false = self.emit(Instr::JumpFalse(0));
+            self.body(t, arity);
+            self.patch(jump_false);
+            self.body(e, arity);
+            return;
+        }
+        if binop(term).is_some() {
+            self.strict(term, 0);
+        } else {
+            self.construct(term, 0);
+        }
+        self.emit(Instr::Update(arity));
+        self.emit(Instr::Pop(arity));
+        self.emit(Instr::Unwind);
+    }
+}
+
+pub fn compile(arity: usize, body: TermRef) -> Vec<Instr> {
+    let mut compiler = Compiler { code: Vec::new() };
+    compiler.body(body, arity);
+    compiler.code
+}
+
+// The same code with its globals rooted, a new one if any of them moved.
+pub fn root_code(code: &Code, roots: &mut dyn Roots,
+                 name: &dyn fmt::Display) -> Code {
+    let mut moved = false;
+    let rooted: Vec<Instr> = code.iter().map(|instr| {
+        match instr {
+            Instr::PushGlobal(t) => {
+                let u = roots.root(*t, name);
+                moved |= u.index() != t.index();
+                Instr::PushGlobal(u)
+            }
+            _ => *instr,
+        }
+    }).collect();
+    if moved { Arc::new(rooted) } else { code.clone() }
+}
+
+// Code a task is running is saved with it, an instruction is a byte
+// followed by its operand.
+pub fn code_save(code: &Code, writer: &mut Writer) -> Result<(),String> {
+    writer.u32(code.len() as u32);
+    for instr in code.iter() {
+        match *instr {
+            Instr::Push(k) => { writer.u8(0); writer.u32(k as u32); }
+            Instr::PushInt(n) => { writer.u8(1); writer.u64(n as u64); }
+            Instr::PushGlobal(t) => { writer.u8(2); writer.term(t)?; }
+            Instr::MkAp => writer.u8(3),
+            Instr::Eval => writer.u8(4),
+            Instr::Add => writer.u8(5),
+            Instr::Sub => writer.u8(6),
+            Instr::Mul => writer.u8(7),
+            Instr::Div => writer.u8(8),
+            Instr::Gt => writer.u8(9),
+            Instr::Lt => writer.u8(10),
+            Instr::Eq => writer.u8(11),
+            Instr::Jump(l) => { writer.u8(12); writer.u32(l as u32); }
+            Instr::JumpFalse(l) => { writer.u8(13); writer.u32(l as u32); }
+            Instr::Update(n) => { writer.u8(14); writer.u32(n as u32); }
+            Instr::Pop(n) => { writer.u8(15); writer.u32(n as u32); }
+            Instr::Unwind => writer.u8(16),
+        }
+    }
+    Ok(())
+}
+
+pub fn code_load(reader: &mut Reader) -> Result<Code,String> {
+    let mut code = Vec::new();
+    for _ in 0..reader.u32()? {
+        let instr = match reader.u8()? {
+            0 => Instr::Push(reader.u32()? as usize),
+            1 => Instr::PushInt(reader.u64()? as i64),
+            2 => Instr::PushGlobal(reader.term()?),
+            3 => Instr::MkAp,
+            4 => Instr::Eval,
+            5 => Instr::Add,
+            6 => Instr::Sub,
+            7 => Instr::Mul,
+            8 => Instr::Div,
+            9 => Instr::Gt,
+            10 => Instr::Lt,
+            11 => Instr::Eq,
+            12 => Instr::Jump(reader.u32()? as usize),
+            13 => Instr::JumpFalse(reader.u32()? as usize),
+            14 => Instr::Update(reader.u32()? as usize),
+            15 => Instr::Pop(reader.u32()? as usize),
+            16 => Instr::Unwind,
+            tag => { return Err(format!("Bad instruction {}!", tag)); }
+        };
+        code.push(instr);
+    }
+    // the code runs until an UNWIND, never past its end
+    let len = code.len();
+    let jumps_in = code.iter().all(|instr| match *instr {
+        Instr::Jump(l) | Instr::JumpFalse(l) => l < len,
+        _ => true,
+    });
+    if !jumps_in || !matches!(code.last(), Some(Instr::Unwind)) {
+        return Err("Bad code in image!".to_string());
+    }
+    Ok(Arc::new(code))
+}
+
+pub fn compile_super(id: usize) {
+    if let Some((arity,body)) = lift::get(id) {
+        lift::set_code(id, compile(arity, body));
+    }
+}
+
+// Print the code of the supercombinators `term` uses, false if none of
+// them has any.
+pub fn show_bytecode(term: TermRef) -> bool {
+    let mut found = false;
+    for id in lift::reachable(term) {
+        if let Some(code) = lift::code(id) {
+            let (arity,_) = lift::get(id).unwrap();
+            println!("${} ({} arguments):", lift::name(id), arity);
+            for (pc,instr) in code.iter().enumerate() {
+                println!("{:>4}  {:?}", pc, instr);
+            }
+            found = true;
+        }
+    }
+    found
+}
+
+#[test]
+pub fn bytecode_test() {
+    use crate::compile;
+    for (name,term) in compile::test_programs() {
+        let ski = compile::optimize(compile::compile_ski(term));
+        let lifted = lift::lift(&name, term);
+        for id in lift::reachable(lifted) {
+            compile_super(id);
+        }
+        let (ret1,_) = compile::reduce_steps(ski);
+        let (ret2,_) = compile::reduce_steps(lifted);
+        assert_eq!(ret1, ret2, "{}", name);
+    }
+}
@@ -1,14 +1,24 @@
 use std::ptr;
+use std::fmt;
 use std::mem;
-use std::sync::Mutex;
-use std::cell::RefCell;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, RwLock};
+use std::cell::{Cell, RefCell};
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicU32, AtomicUsize, Ordering};
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+use std::thread;
 
 //use crate::term;
 use crate::symbol;
 use crate::term::{Term, TermRef};
 use crate::eval;
+use crate::eval::Task;
 use crate::task;
+use crate::spark;
+use crate::chan;
+use crate::lift;
+use crate::term;
+use crate::verify;
 
 pub unsafe fn malloc<T>(size: usize) -> *mut T {
     if size == 0 { return ptr::null_mut(); }
@@ -37,12 +47,100 @@ pub fn malloc_and_free_test() {
 
 
 lazy_static::lazy_static! {
+    // the nursery of each worker: pages it filled since the last gc
+    static ref NURSERY: RwLock<Vec<Mutex<Vec<Page>>>> =
+                            RwLock::new(Vec::new());
+    // pages handed to the nursery from outside the workers
     static ref DUMP_POOL: Mutex<Vec<Page>> = 
                             Mutex::new(Vec::new());
+    // the old generation: survivors of the nursery
+    static ref OLD_POOL: Mutex<Vec<Page>> =
+                            Mutex::new(Vec::new());
+    // pages of the definitions (dictionary, link cells, images), they
+    // are never collected and only point to each other
+    static ref PERM_POOL: Mutex<Vec<Page>> =
+                            Mutex::new(Vec::new());
+    // permanent or old nodes updated in place, they may point into
+    // younger pages (the write barrier is term_update), by index so a
+    // node updated again and again is remembered once
+    static ref REMEMBERED: Mutex<HashMap<u32,TermRef>> =
+                            Mutex::new(HashMap::new());
+    // dead cells of the old generation, found by the last sweep
+    static ref OLD_FREE: Mutex<Vec<TermRef>> =
+                            Mutex::new(Vec::new());
+    static ref MARKING: Mutex<Option<Marking>> =
+                            Mutex::new(None);
+    // children overwritten while a mark cycle is running
+    static ref SATB: Mutex<Vec<TermRef>> =
+                            Mutex::new(Vec::new());
+    static ref GC_LOG: Mutex<VecDeque<GcLog>> =
+                            Mutex::new(VecDeque::new());
+    static ref STOP_TIME: Mutex<Option<Instant>> =
+                            Mutex::new(None);
+}
+
+// nursery pages per worker before a minor gc
+static NURSERY_SIZE: AtomicUsize = AtomicUsize::new(4);
+// old pages before a major gc, grows with the live data
+static WATERMARK: AtomicUsize = AtomicUsize::new(32);
+static OLD_LIMIT: AtomicUsize = AtomicUsize::new(32);
+static PAGE_SIZE: AtomicUsize = AtomicUsize::new(65536);
+// bytes of old and permanent pages allowed after a major gc, 0 for
+// no limit
+static MAX_HEAP: AtomicUsize = AtomicUsize::new(1 << 30);
+
+static GC_COUNT: AtomicUsize = AtomicUsize::new(0);
+static GC_VERBOSE: AtomicBool = AtomicBool::new(false);
+static FORCE_MAJOR: AtomicBool = AtomicBool::new(false);
+static MARK_ACTIVE: AtomicBool = AtomicBool::new(false);
+// old nodes marked in each pause while a cycle is running
+static MARK_SLICE: AtomicUsize = AtomicUsize::new(1 << 18);
+static LOG_MAX: usize = 16;
+
+#[derive(Clone,Debug)]
+pub struct GcLog {
+    pub major: bool,
+    pub scanned: usize,
+    pub live: usize,
+    pub marked: usize,
+    pub pause: Duration,
+    pub time: Duration,
+}
+
+impl GcLog {
+    pub fn bytes_copied(&self) -> usize {
+        self.live * mem::size_of::<Term>()
+    }
+}
+
+pub fn page_size() -> usize {
+    PAGE_SIZE.load(Ordering::Relaxed)
+}
+// only affects pages allocated from now on
+pub fn set_page_size(n: usize) {
+    assert!(n > 0 && n <= PAGE_MAX);
+    PAGE_SIZE.store(n, Ordering::Relaxed);
+}
+pub fn set_nursery_size(n: usize) {
+    assert!(n > 0 && n <= NURSERY_MAX);
+    NURSERY_SIZE.store(n, Ordering::Relaxed);
+}
+pub fn set_watermark(n: usize) {
+    assert!(n > 0);
+    WATERMARK.store(n, Ordering::SeqCst);
+    OLD_LIMIT.store(n, Ordering::SeqCst);
+}
+pub fn set_max_heap(bytes: usize) {
+    MAX_HEAP.store(bytes, Ordering::SeqCst);
+}
+pub fn set_mark_slice(n: usize) {
+    assert!(n > 0);
+    MARK_SLICE.store(n, Ordering::Relaxed);
 }
 
-static WATERMARK: usize = 32;
-static PAGE_SIZE: usize = 65536;
+fn pool_bytes(pool: &[Page]) -> usize {
+    pool.iter().map(|page| page.size).sum::<usize>() * mem::size_of::<Term>()
+}
 
 static STW_SINGAL: AtomicBool = AtomicBool::new(true);
 pub fn singal_running() -> bool {
@@ -53,12 +151,93 @@ pub fn set_singal_run() {
     STW_SINGAL.store(true,Ordering::Relaxed);
 }
 pub fn set_singal_stop() {
+    STOP_TIME.lock().unwrap().get_or_insert_with(Instant::now);
     STW_SINGAL.store(false,Ordering::Relaxed);
+    task::wake_all();
 }
 
 thread_local! {
     pub static PAGE : RefCell<Page> =
-                RefCell::new(Page::new(PAGE_SIZE));
+                RefCell::new(Page::new(page_size()));
+    // pages filled outside the workers, the gc doesn't see them until
+    // `hand_over` gives them to the nursery
+    static PRIVATE: RefCell<Vec<Page>> = const { RefCell::new(Vec::new()) };
+    // the permanent page while PAGE isn't one, swapped by `perm`
+    static SPARE: RefCell<Page> = RefCell::new(Page::new(0));
+    static IN_PERM: Cell<bool> = const { Cell::new(false) };
+}
+
+// Every page owns a slot in PAGE_TABLE while it is alive, a TermRef
+// is the slot number followed by the offset in the page.
+pub const OFFSET_BITS: usize = 20;
+pub const SLOT_MAX: usize = 1 << (32 - OFFSET_BITS);
+pub const PAGE_MAX: usize = 1 << OFFSET_BITS;
+// a full nursery of every worker still leaves most slots to the
+// old generation
+pub const NURSERY_MAX: usize = SLOT_MAX / 64;
+
+static PAGE_TABLE: [AtomicPtr<Term>; SLOT_MAX] =
+                [const { AtomicPtr::new(ptr::null_mut()) }; SLOT_MAX];
+static PAGE_GEN: [AtomicU32; SLOT_MAX] =
+                [const { AtomicU32::new(0) }; SLOT_MAX];
+static PAGE_LEN: [AtomicU32; SLOT_MAX] =
+                [const { AtomicU32::new(0) }; SLOT_MAX];
+// pages of PERM_POOL
+static PAGE_PERM: [AtomicBool; SLOT_MAX] =
+                [const { AtomicBool::new(false) }; SLOT_MAX];
+
+lazy_static::lazy_static! {
+    // slot 0 belongs to the constants
+    static ref FREE_SLOT: Mutex<Vec<usize>> =
+                            Mutex::new((1..SLOT_MAX).rev().collect());
+}
+
+pub fn free_slots() -> usize {
+    FREE_SLOT.lock().unwrap().len()
+}
+
+#[inline]
+pub fn term_ptr(term: &TermRef) -> *mut Term {
+    let slot = term.slot();
+    #[cfg(debug_assertions)]
+    assert_eq!(PAGE_GEN[slot].load(Ordering::Acquire), term.gen(),
+        "TermRef into a freed page!");
+    let base = PAGE_TABLE[slot].load(Ordering::Acquire);
+    unsafe { base.add(term.offset()) }
+}
+
+// Permanent nodes are shared by definitions, only the gc barrier may
+// write into them.
+pub fn is_perm(term: TermRef) -> bool {
+    term.slot() == 0 || PAGE_PERM[term.slot()].load(Ordering::Acquire)
+}
+
+pub fn slot_gen(slot: usize) -> u32 {
+    PAGE_GEN[slot].load(Ordering::Acquire)
+}
+
+// Whether a TermRef can be followed, without following it.
+pub fn check_ref(term: TermRef) -> Result<(),String> {
+    let slot = term.slot();
+    if slot == 0 {
+        if term.offset() < term::const_count() {
+            return Ok(());
+        }
+        return Err(format!("#{} is not a constant", term.index()));
+    }
+    if PAGE_TABLE[slot].load(Ordering::Acquire).is_null() {
+        return Err(format!("#{} points to a freed page", term.index()));
+    }
+    #[cfg(debug_assertions)]
+    if PAGE_GEN[slot].load(Ordering::Acquire) != term.gen() {
+        return Err(format!("#{} points to a reused page slot",
+            term.index()));
+    }
+    if term.offset() >= PAGE_LEN[slot].load(Ordering::Acquire) as usize {
+        return Err(format!("#{} is past the end of its page",
+            term.index()));
+    }
+    Ok(())
 }
 
 #[derive(Debug)]
@@ -66,6 +245,7 @@ pub struct Page {
     array: *mut Term,
     size: usize,
     index: usize,
+    slot: usize,
 }
 
 unsafe impl Send for Page {}
@@ -73,6 +253,13 @@ unsafe impl Sync for Page {}
 
 impl Drop for Page {
     fn drop(&mut self) {
+        if self.slot != 0 {
+            PAGE_TABLE[self.slot].store(ptr::null_mut(), Ordering::Release);
+            PAGE_LEN[self.slot].store(0, Ordering::Release);
+            PAGE_GEN[self.slot].fetch_add(1, Ordering::Release);
+            PAGE_PERM[self.slot].store(false, Ordering::Release);
+            FREE_SLOT.lock().unwrap().push(self.slot);
+        }
         unsafe { free(self.array,self.size) };
         //println!("free {:?}", self.array);
     }
@@ -82,7 +269,27 @@ impl Page {
     pub fn new(size: usize) -> Page {
         let array: *mut Term = unsafe { malloc(size) };
         //assert!(!array.is_null());
-        Page { array: array, size: size, index: 0 }
+        let slot = if size == 0 { 0 } else {
+            let slot = FREE_SLOT.lock().unwrap().pop()
+                .expect("Run out of page slots!");
+            PAGE_TABLE[slot].store(array, Ordering::Release);
+            PAGE_LEN[slot].store(size as u32, Ordering::Release);
+            slot
+        };
+        Page { array, size, index: 0, slot }
+    }
+    fn term_at(&self, index: usize) -> TermRef {
+        TermRef::new(self.slot, index)
+    }
+    // bump allocation, None when the page is full
+    fn alloc(&mut self, term: Term) -> Option<TermRef> {
+        if self.index < self.size {
+            unsafe { ptr::write(self.array.add(self.index), term); }
+            self.index += 1;
+            Some(self.term_at(self.index - 1))
+        } else {
+            None
+        }
     }
 }
 
@@ -92,37 +299,469 @@ pub trait GCable {
 }
 */
 
+// Something that holds on to terms, the gc moves them and the
+// verifier checks them. `name` says where the root came from.
+pub trait Roots {
+    fn root(&mut self, term: TermRef, name: &dyn fmt::Display) -> TermRef;
+}
+
 pub fn drain_dump() -> Vec<Page> {
-    let mut dump = DUMP_POOL.lock().unwrap();
-    dump.drain(..).collect()
+    let mut dump: Vec<Page> = DUMP_POOL.lock().unwrap().drain(..).collect();
+    for pages in NURSERY.read().unwrap().iter() {
+        dump.append(&mut pages.lock().unwrap());
+    }
+    dump
+}
+
+fn nursery_pages() -> usize {
+    NURSERY.read().unwrap().iter()
+        .map(|pages| pages.lock().unwrap().len())
+        .sum::<usize>() + DUMP_POOL.lock().unwrap().len()
+}
+
+// Add a page to the nursery of a worker, returns its size in pages.
+fn nursery_push(id: usize, page: Page) -> usize {
+    {
+        let nursery = NURSERY.read().unwrap();
+        if let Some(pages) = nursery.get(id) {
+            let mut pages = pages.lock().unwrap();
+            pages.push(page);
+            return pages.len();
+        }
+    }
+    let mut nursery = NURSERY.write().unwrap();
+    while nursery.len() <= id {
+        nursery.push(Mutex::new(Vec::new()));
+    }
+    let mut pages = nursery[id].lock().unwrap();
+    pages.push(page);
+    pages.len()
+}
+
+// Copying collector for the nursery. The roots are forwarded first,
+// then the grey stack is drained until every promoted node has been
+// scanned. Every copied node leaves a `Moved` behind, so sharing and
+// cycles survive the collection. Promoted nodes fill the free cells
+// of the old generation before any fresh page.
+pub struct Collector {
+    from: Vec<bool>,
+    to: Vec<Page>,
+    free: Vec<TermRef>,
+    reused: Vec<TermRef>,
+    grey: Vec<TermRef>,
+    copied: usize,
+}
+
+impl Roots for Collector {
+    fn root(&mut self, term: TermRef, _: &dyn fmt::Display) -> TermRef {
+        self.forward(term)
+    }
+}
+
+impl Collector {
+    pub fn new(from: &[Page]) -> Collector {
+        let mut slots = vec![false; SLOT_MAX];
+        for page in from {
+            slots[page.slot] = page.slot != 0;
+        }
+        Collector {
+            from: slots,
+            to: vec![Page::new(page_size())],
+            free: Vec::new(),
+            reused: Vec::new(),
+            grey: Vec::new(),
+            copied: 0,
+        }
+    }
+    fn in_from(&self, term: TermRef) -> bool {
+        self.from[term.slot()]
+    }
+    fn alloc(&mut self, term: Term) -> TermRef {
+        self.copied += 1;
+        if let Some(cell) = self.free.pop() {
+            unsafe { cell.set(term); }
+            self.reused.push(cell);
+            return cell;
+        }
+        if let Some(new) = self.to.last_mut().unwrap().alloc(term) {
+            return new;
+        }
+        let mut page = Page::new(page_size());
+        let new = page.alloc(term).unwrap();
+        self.to.push(page);
+        new
+    }
+    pub fn forward(&mut self, term: TermRef) -> TermRef {
+        if !self.in_from(term) {
+            return term;
+        }
+        if let Term::Moved(new) = *term {
+            return new;
+        }
+        let new = self.alloc(*term);
+        unsafe { term.set(Term::Moved(new)); }
+        self.grey.push(new);
+        new
+    }
+    fn scan_node(&mut self, node: TermRef) {
+        match *node {
+            Term::App(t1,t2) => {
+                let new = Term::App(self.forward(t1),self.forward(t2));
+                unsafe { node.set(new); }
+            }
+            Term::Lam(x,t) => {
+                let new = Term::Lam(x,self.forward(t));
+                unsafe { node.set(new); }
+            }
+            _ => {}
+        }
+    }
+    fn scan(&mut self) {
+        while let Some(node) = self.grey.pop() {
+            self.scan_node(node);
+        }
+    }
+}
+
+// Incremental mark-sweep for the old generation, which never moves.
+// A cycle greys the roots at the end of a minor gc, then every pause
+// marks another slice. Children overwritten by term_update are greyed
+// too (snapshot at the beginning), and nodes promoted during the
+// cycle are born marked. Once nothing is grey, the unmarked cells
+// become the free list and pages with nothing marked are released.
+struct Marking {
+    // the old pages when the cycle started, one bit per cell
+    marks: HashMap<usize,Vec<bool>>,
+    grey: Vec<TermRef>,
+    marked: usize,
+}
+
+impl Roots for Marking {
+    fn root(&mut self, term: TermRef, _: &dyn fmt::Display) -> TermRef {
+        self.grey.push(term);
+        term
+    }
+}
+
+impl Marking {
+    fn new(old: &[Page]) -> Marking {
+        Marking {
+            marks: old.iter()
+                .map(|page| (page.slot, vec![false; page.size]))
+                .collect(),
+            grey: Vec::new(),
+            marked: 0,
+        }
+    }
+    fn mark(&mut self, node: TermRef) -> bool {
+        if let Some(bits) = self.marks.get_mut(&node.slot()) {
+            if !bits[node.offset()] {
+                bits[node.offset()] = true;
+                self.marked += 1;
+                return true;
+            }
+        }
+        false
+    }
+    // false once nothing is left to mark
+    fn step(&mut self, budget: usize) -> bool {
+        let mut budget = budget;
+        while budget > 0 {
+            let node = match self.grey.pop() {
+                Some(node) => node,
+                None => { return false; }
+            };
+            if !self.mark(node) {
+                continue;
+            }
+            budget -= 1;
+            match *node {
+                Term::App(t1,t2) => {
+                    self.grey.push(t2);
+                    self.grey.push(t1);
+                }
+                Term::Lam(_,t) | Term::Array(_,t) => {
+                    self.grey.push(t);
+                }
+                _ => {}
+            }
+        }
+        true
+    }
+    fn sweep(&self, old: &mut Vec<Page>) -> Vec<TermRef> {
+        let mut free = Vec::new();
+        old.retain_mut(|page| {
+            if let Some(bits) = self.marks.get(&page.slot) {
+                if !bits.iter().any(|bit| *bit) {
+                    return false;
+                }
+                for (i,bit) in bits.iter().enumerate() {
+                    if !bit {
+                        free.push(page.term_at(i));
+                    }
+                }
+                // the unused tail is handed out as free cells
+                page.index = page.size;
+            }
+            true
+        });
+        free
+    }
+}
+
+fn start_marking(old: &[Page], tasks: &mut [Task]) -> Marking {
+    let mut marking = Marking::new(old);
+    for task in tasks.iter_mut() {
+        eval::task_roots(task, &mut marking);
+    }
+    symbol::dict_roots(&mut marking);
+    symbol::cons_roots(&mut marking);
+    lift::super_roots(&mut marking);
+    spark::spark_roots(&mut marking);
+    chan::chan_roots(&mut marking);
+    // permanent nodes are not traced, only the ones updated in place
+    // may point into the old generation
+    for node in REMEMBERED.lock().unwrap().values() {
+        push_children(&mut marking.grey, *node);
+    }
+    MARK_ACTIVE.store(true, Ordering::SeqCst);
+    marking
+}
+
+fn push_children(grey: &mut Vec<TermRef>, node: TermRef) {
+    match *node {
+        Term::App(t1,t2) => {
+            grey.push(t1);
+            grey.push(t2);
+        }
+        Term::Lam(_,t) | Term::Array(_,t) => {
+            grey.push(t);
+        }
+        _ => {}
+    }
+}
+
+fn old_bytes(old: &[Page], free: usize) -> usize {
+    pool_bytes(old) - free * mem::size_of::<Term>()
 }
 
+// Runs with every worker stopped, the nurseries are not collected
+// independently: a worker can hold terms of another one's nursery
+// through stolen tasks, sparks and chans. The pause is kept short
+// instead. The nurseries are always promoted, the old generation is
+// marked a slice at a time, or all at once when the gc is forced.
 pub fn run_gc() {
     //println!("gc_start");
-    let _dump = drain_dump();
+    let start = Instant::now();
+    let from = drain_dump();
     let mut vec = task::drain_task();
-    while let Some(mut task) = vec.pop() {
-        eval::task_copy(&mut task);
+    if verify::enabled() {
+        verify::verify_heap("before gc", &mut vec, None);
+    }
+    let mut old = OLD_POOL.lock().unwrap();
+    let mut gc = Collector::new(&from);
+    gc.free = mem::take(&mut *OLD_FREE.lock().unwrap());
+    // terms copied for each task, shared ones count for the first
+    let mut owned = Vec::new();
+    for task in &mut vec {
+        let before = gc.copied;
+        eval::task_roots(task, &mut gc);
+        gc.scan();
+        owned.push(gc.copied - before);
+    }
+    symbol::dict_roots(&mut gc);
+    symbol::cons_roots(&mut gc);
+    lift::super_roots(&mut gc);
+    spark::spark_roots(&mut gc);
+    chan::chan_roots(&mut gc);
+    for term in SATB.lock().unwrap().iter_mut() {
+        *term = gc.forward(*term);
+    }
+    {
+        let mut old_slots = vec![false; SLOT_MAX];
+        for page in old.iter() {
+            old_slots[page.slot] = true;
+        }
+        let mut remembered = REMEMBERED.lock().unwrap();
+        // nodes in from-space are copied with their new content anyway
+        for node in remembered.values() {
+            if !gc.in_from(*node) {
+                gc.scan_node(*node);
+            }
+        }
+        // old nodes only point to the old generation from now on, the
+        // permanent ones are needed again by the marking
+        remembered.retain(|_,node| !gc.in_from(*node)
+                                    && !old_slots[node.slot()]);
+    }
+    gc.scan();
+    let mut free = mem::take(&mut gc.free);
+    let mut marking = MARKING.lock().unwrap();
+    if let Some(marking) = marking.as_mut() {
+        for cell in &gc.reused {
+            marking.mark(*cell);
+        }
+    }
+    old.append(&mut gc.to);
+
+    let max_heap = MAX_HEAP.load(Ordering::SeqCst);
+    let perm = pool_bytes(&PERM_POOL.lock().unwrap());
+    let force = FORCE_MAJOR.swap(false, Ordering::SeqCst);
+    if force {
+        // finish the running cycle first, its snapshot may be stale
+        if let Some(mut running) = marking.take() {
+            running.grey.append(&mut SATB.lock().unwrap());
+            running.step(usize::MAX);
+            free = running.sweep(&mut old);
+        }
+    }
+    let over_limit = old_bytes(&old, free.len())
+        >= OLD_LIMIT.load(Ordering::SeqCst) * page_size()
+                                            * mem::size_of::<Term>()
+        || (max_heap > 0 && old_bytes(&old, free.len()) + perm > max_heap);
+    if marking.is_none() && (force || over_limit) {
+        *marking = Some(start_marking(&old, &mut vec));
+    }
+    let mut major = false;
+    let mut marked = 0;
+    if let Some(running) = marking.as_mut() {
+        running.grey.append(&mut SATB.lock().unwrap());
+        let budget = if force { usize::MAX }
+                     else { MARK_SLICE.load(Ordering::Relaxed) };
+        if !running.step(budget) {
+            let running = marking.take().unwrap();
+            MARK_ACTIVE.store(false, Ordering::SeqCst);
+            free = running.sweep(&mut old);
+            major = true;
+            marked = running.marked;
+        }
+    }
+    drop(marking);
+    if major {
+        let live = old_bytes(&old, free.len());
+        let pages = live / (page_size() * mem::size_of::<Term>());
+        let limit = WATERMARK.load(Ordering::SeqCst).max(pages * 2);
+        OLD_LIMIT.store(limit, Ordering::SeqCst);
+        if max_heap > 0 && live + perm > max_heap {
+            // fail the task holding most of the live data, its terms
+            // are released by the next mark cycle
+            let index = (0..owned.len()).max_by_key(|i| owned[*i]);
+            if let Some(index) = index {
+                task::fail_task(vec.remove(index),
+                    "Out of memory!".to_string());
+                FORCE_MAJOR.store(true, Ordering::SeqCst);
+            } else {
+                println!("Heap limit exceeded without any task!");
+            }
+        }
+    }
+    *OLD_FREE.lock().unwrap() = free;
+    drop(old);
+    if let Some(mut reclaimer) = lift::Reclaimer::new() {
+        for task in &mut vec {
+            eval::task_roots(task, &mut reclaimer);
+        }
+        symbol::dict_roots(&mut reclaimer);
+        spark::spark_roots(&mut reclaimer);
+        chan::chan_roots(&mut reclaimer);
+        let reclaimed = reclaimer.reclaim();
+        if GC_VERBOSE.load(Ordering::Relaxed) {
+            println!("gc: {} supercombinators reclaimed", reclaimed);
+        }
+    }
+    if verify::enabled() {
+        verify::verify_heap("after gc", &mut vec, Some(&gc.from));
+    }
+    let scanned = from.iter().map(|page| page.index).sum();
+    drop(from);
+    for task in vec {
         task::send_task(task);
     }
-    symbol::dict_copy();
+    let stop = STOP_TIME.lock().unwrap().take().unwrap_or(start);
+    log_gc(GcLog {
+        major,
+        scanned,
+        live: gc.copied,
+        marked,
+        pause: stop.elapsed(),
+        time: start.elapsed(),
+    });
     //println!("gc_end");
 }
 
+fn log_gc(log: GcLog) {
+    if GC_VERBOSE.load(Ordering::Relaxed) {
+        println!("[gc] {} live: {} dead: {} copied: {}B marked: {} \
+                  pause: {:?}",
+            if log.major { "major" } else { "minor" },
+            log.live, log.scanned.saturating_sub(log.live),
+            log.bytes_copied(), log.marked, log.pause);
+    }
+    let mut logs = GC_LOG.lock().unwrap();
+    if logs.len() == LOG_MAX {
+        logs.pop_front();
+    }
+    logs.push_back(log);
+    drop(logs);
+    GC_COUNT.fetch_add(1, Ordering::SeqCst);
+}
+
+pub fn set_gc_verbose(verbose: bool) {
+    GC_VERBOSE.store(verbose, Ordering::Relaxed);
+}
+
+// Stop the workers and wait for a major gc to finish.
+pub fn force_gc() {
+    let count = GC_COUNT.load(Ordering::SeqCst);
+    FORCE_MAJOR.store(true, Ordering::SeqCst);
+    set_singal_stop();
+    while GC_COUNT.load(Ordering::SeqCst) == count {
+        thread::sleep(Duration::from_millis(1));
+    }
+}
+
+pub fn show_heap() {
+    println!("nursery: {} pages, old: {} pages, permanent: {} pages",
+        nursery_pages(),
+        OLD_POOL.lock().unwrap().len(),
+        PERM_POOL.lock().unwrap().len());
+    println!("page size: {} terms, {} bytes per term",
+        page_size(), mem::size_of::<Term>());
+    println!("free old cells: {}, marking: {}",
+        OLD_FREE.lock().unwrap().len(),
+        if MARK_ACTIVE.load(Ordering::SeqCst) { "running" } else { "idle" });
+    println!("collections: {}", GC_COUNT.load(Ordering::SeqCst));
+    for log in GC_LOG.lock().unwrap().iter() {
+        println!("{} live: {} dead: {} copied: {}B marked: {} \
+                  pause: {:?} gc: {:?}",
+            if log.major { "major" } else { "minor" },
+            log.live, log.scanned.saturating_sub(log.live),
+            log.bytes_copied(), log.marked, log.pause, log.time);
+    }
+}
+
+// Overwrite a node in place, remembering it if it may now point
+// into a page the gc is going to move.
+pub fn remembered() -> Vec<TermRef> {
+    REMEMBERED.lock().unwrap().values().copied().collect()
+}
+
+pub fn term_update(node: TermRef, term: Term) {
+    if MARK_ACTIVE.load(Ordering::Relaxed) {
+        push_children(&mut SATB.lock().unwrap(), node);
+    }
+    unsafe { node.set(term); }
+    match term {
+        Term::App(_,_) | Term::Lam(_,_) => {
+            REMEMBERED.lock().unwrap().insert(node.index(), node);
+        }
+        _ => {}
+    }
+}
+
 pub fn term_alloc(term: Term) -> TermRef {
-    let mut result : Option<TermRef> = None;
     loop {
-        PAGE.with(|page| {
-            let mut p = page.borrow_mut();
-            if p.index < p.size {
-                unsafe {
-                    let ptr = p.array.offset(p.index as isize);
-                    *ptr = term;
-                    p.index += 1;
-                    result = Some(TermRef::new(ptr));
-                }
-            }
-        });
+        let result = PAGE.with(|page| page.borrow_mut().alloc(term));
         if let Some(term) = result {
             return term;       
         } else {
@@ -131,24 +770,158 @@ pub fn term_alloc(term: Term) -> TermRef {
     }
 }
 
+// Pages filled by hand, e.g. by the image loader, and handed to the
+// heap once the terms in them are complete.
+pub struct Arena {
+    pages: Vec<Page>,
+}
+
+impl Arena {
+    pub fn new() -> Arena {
+        Arena { pages: Vec::new() }
+    }
+    pub fn alloc(&mut self, term: Term) -> TermRef {
+        if let Some(page) = self.pages.last_mut() {
+            if let Some(new) = page.alloc(term) {
+                return new;
+            }
+        }
+        let mut page = Page::new(page_size());
+        let new = page.alloc(term).unwrap();
+        self.pages.push(page);
+        new
+    }
+    // never collected, like the pages of the other definitions
+    pub fn into_perm(self) {
+        for page in &self.pages {
+            PAGE_PERM[page.slot].store(true, Ordering::Release);
+        }
+        PERM_POOL.lock().unwrap().extend(self.pages);
+    }
+    // The pages join the nursery, `send` has to make their terms
+    // reachable before the next gc can look at the nursery.
+    pub fn into_nursery(self, send: impl FnOnce()) {
+        let mut dump = DUMP_POOL.lock().unwrap();
+        dump.extend(self.pages);
+        send();
+    }
+}
+
+// Terms made by `f` go to permanent pages, for the definitions every
+// task shares.
+pub fn perm<T>(f: impl FnOnce() -> T) -> T {
+    if IN_PERM.with(|flag| flag.replace(true)) {
+        return f();
+    }
+    let swap = || PAGE.with(|page| SPARE.with(|spare| page.swap(spare)));
+    swap();
+    let result = f();
+    swap();
+    IN_PERM.with(|flag| flag.set(false));
+    result
+}
+
 pub fn next_page() {
     //println!("refresh");
+    let perm = IN_PERM.with(|flag| flag.get());
     PAGE.with(|page| {
-        let page2 = RefCell::new(Page::new(PAGE_SIZE));
+        let page2 = RefCell::new(Page::new(page_size()));
+        if perm {
+            PAGE_PERM[page2.borrow().slot].store(true, Ordering::Release);
+        }
         page.swap(&page2);
-        let mut dump = DUMP_POOL.lock().unwrap();
-        dump.push(page2.into_inner());
-        if dump.len() >= WATERMARK {
-            set_singal_stop();
+        let full = page2.into_inner();
+        // the empty page left by dump_page, or the first spare, holds
+        // nothing and doesn't count for the nursery
+        if full.size == 0 {
+            return;
+        }
+        if perm {
+            PERM_POOL.lock().unwrap().push(full);
+        } else if let Some(id) = task::worker_id() {
+            // a worker with a full nursery asks for a minor gc
+            if nursery_push(id, full)
+                >= NURSERY_SIZE.load(Ordering::Relaxed) {
+                set_singal_stop();
+            }
+        } else {
+            PRIVATE.with(|pages| pages.borrow_mut().push(full));
         }
     })
 }
 
-pub fn dump_page() {
+// Give the pages filled outside the workers to the nursery. Nothing
+// the gc sees may point into them before, `send` makes their terms
+// reachable before the next gc can look at the nursery.
+pub fn hand_over(send: impl FnOnce()) {
+    assert!(task::worker_id().is_none() && !IN_PERM.with(|flag| flag.get()));
     let mut dump = DUMP_POOL.lock().unwrap();
+    PRIVATE.with(|pages| dump.append(&mut pages.borrow_mut()));
+    PAGE.with(|page| {
+        if page.borrow().index > 0 {
+            dump.push(page.replace(Page::new(page_size())));
+        }
+    });
+    send();
+}
+
+// Hand the page being filled to the nursery, before a gc or when the
+// worker exits.
+pub fn dump_page() {
     PAGE.with(|page| {
         let page2 = RefCell::new(Page::new(0));
         page.swap(&page2);
-        dump.push(page2.into_inner());
+        let full = page2.into_inner();
+        if full.size == 0 {
+            return;
+        }
+        if let Some(id) = task::worker_id() {
+            nursery_push(id, full);
+        } else {
+            DUMP_POOL.lock().unwrap().push(full);
+        }
     })
-}
\ No newline at end of file
+}
+
+#[test]
+pub fn nursery_one_test() {
+    thread::spawn(|| {
+        // a worker no one else is, its nursery starts empty
+        let id = NURSERY_MAX;
+        task::become_worker(id);
+        let pages = || NURSERY.read().unwrap()[id].lock().unwrap().len();
+        term_alloc(Term::DInt(1));
+        dump_page();
+        dump_page();
+        assert_eq!(pages(), 1);
+        // with --nursery 1 the empty page would ask for a gc at once
+        term_alloc(Term::DInt(2));
+        assert_eq!(pages(), 1);
+    }).join().unwrap();
+}
+#[test]
+pub fn gc_sharing_test() {
+    let mut from = vec![Page::new(16)];
+    let node = |i: usize| TermRef::new(from[0].slot, i);
+    let n0 = node(0);
+    let n1 = node(1);
+    let n2 = node(2);
+    from[0].alloc(Term::DInt(42));
+    from[0].alloc(Term::App(n0,n0));
+    // a cycle: x = App(x, 42)
+    from[0].alloc(Term::App(n2,n0));
+    let mut gc = Collector::new(&from);
+    let shared = gc.forward(n1);
+    let cycle = gc.forward(n2);
+    gc.scan();
+    assert!(!gc.in_from(shared) && !gc.in_from(cycle));
+    if let (Term::App(a,b), Term::App(c,d)) = (*shared, *cycle) {
+        assert_eq!(a.index(), b.index());
+        assert_eq!(a.index(), d.index());
+        assert_eq!(c.index(), cycle.index());
+        assert_eq!(*a, Term::DInt(42));
+    } else {
+        panic!("gc changed the shape of the terms!");
+    }
+    assert_eq!(gc.to[0].index, 3);
+}
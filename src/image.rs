@@ -0,0 +1,578 @@
+use std::collections::HashMap;
+use std::fs;
+use std::mem;
+
+use crate::heap;
+use crate::heap::Arena;
+use crate::term;
+use crate::term::{Term, TermRef};
+use crate::term::Term::*;
+use crate::symbol;
+use crate::symbol::Symb;
+use crate::spark;
+use crate::chan;
+use crate::lift;
+use crate::compile;
+use crate::compile::Backend;
+use crate::gmachine;
+use crate::eval;
+use crate::eval::Task;
+use crate::task;
+
+// An image is a header, the symbol names, the term graph and then the
+// records (dictionary entries, tasks) pointing into it. Numbers are
+// little endian, a reference is either a node number or, with the top
+// bit set, the offset of a constant.
+// compiled files have their own magic, so neither loads as the other
+const IMAGE_MAGIC: &[u8] = b"NRMI";
+const COMPILED_MAGIC: &[u8] = b"NRMC";
+const VERSION: u32 = 1;
+const CONST_BIT: u32 = 1 << 31;
+
+const TAG_APP: u8 = 0;
+const TAG_LAM: u8 = 1;
+const TAG_VAR: u8 = 2;
+const TAG_INT: u8 = 3;
+const TAG_CHAR: u8 = 4;
+const TAG_REAL: u8 = 5;
+const TAG_EAGER: u8 = 6;
+const TAG_CHAN: u8 = 7;
+const TAG_SUPER: u8 = 8;
+const TAG_ARG: u8 = 9;
+const TAG_CODE: u8 = 10;
+// a tag and at least one byte
+const NODE_MIN: usize = 2;
+
+pub struct Writer {
+    magic: &'static [u8],
+    out: Vec<u8>,
+    symbs: Vec<Symb>,
+    symb_ids: HashMap<Symb,u32>,
+    nodes: Vec<TermRef>,
+    node_ids: HashMap<u32,u32>,
+    records: u32,
+}
+
+impl Writer {
+    pub fn new() -> Writer {
+        Writer::with_magic(IMAGE_MAGIC)
+    }
+    fn with_magic(magic: &'static [u8]) -> Writer {
+        Writer {
+            magic,
+            out: Vec::new(),
+            symbs: Vec::new(),
+            symb_ids: HashMap::new(),
+            nodes: Vec::new(),
+            node_ids: HashMap::new(),
+            records: 0,
+        }
+    }
+    pub fn record(&mut self, tag: u8) {
+        self.records += 1;
+        self.u8(tag);
+    }
+    pub fn u8(&mut self, n: u8) {
+        self.out.push(n);
+    }
+    pub fn u32(&mut self, n: u32) {
+        self.out.extend_from_slice(&n.to_le_bytes());
+    }
+    pub fn u64(&mut self, n: u64) {
+        self.out.extend_from_slice(&n.to_le_bytes());
+    }
+    pub fn string(&mut self, string: &str) {
+        self.u32(string.len() as u32);
+        self.out.extend_from_slice(string.as_bytes());
+    }
+    pub fn symb(&mut self, symb: Symb) {
+        let next = self.symbs.len() as u32;
+        let id = *self.symb_ids.entry(symb).or_insert(next);
+        if id == next {
+            self.symbs.push(symb);
+        }
+        self.u32(id);
+    }
+    pub fn term(&mut self, term: TermRef) -> Result<(),String> {
+        let id = self.node_id(term)?;
+        self.u32(id);
+        Ok(())
+    }
+    fn node_id(&mut self, term: TermRef) -> Result<u32,String> {
+        if term.slot() == 0 {
+            return Ok(CONST_BIT | term.offset() as u32);
+        }
+        if let Some(id) = self.node_ids.get(&term.index()) {
+            return Ok(*id);
+        }
+        let id = match *term {
+            // a spark nobody runs yet is saved as its term
+            Spark(n) => {
+                let pending = spark::pending(n).ok_or_else(||
+                    format!("Spark#{} is running, it can't be saved!", n))?;
+                self.node_id(pending)?
+            }
+            Fulfil(_) | Moved(_) | Array(_,_) => {
+                return Err(format!("{:?} can't be saved!", *term));
+            }
+            _ => {
+                if let Some(leaf) = term::find_const(&term) {
+                    CONST_BIT | leaf.offset() as u32
+                } else {
+                    self.nodes.push(term);
+                    self.nodes.len() as u32 - 1
+                }
+            }
+        };
+        self.node_ids.insert(term.index(), id);
+        Ok(id)
+    }
+    fn node(&mut self, node: TermRef) -> Result<(),String> {
+        match *node {
+            App(t1,t2) => {
+                self.u8(TAG_APP);
+                self.term(t1)?;
+                self.term(t2)?;
+            }
+            Lam(x,t) => {
+                self.u8(TAG_LAM);
+                self.symb(x);
+                self.term(t)?;
+            }
+            // links are made again after loading
+            Var(x) | Link(x,_) => {
+                self.u8(TAG_VAR);
+                self.symb(x);
+            }
+            DInt(n) => {
+                self.u8(TAG_INT);
+                self.u64(n as u64);
+            }
+            DChar(c) => {
+                self.u8(TAG_CHAR);
+                self.u32(c as u32);
+            }
+            DReal(x) => {
+                self.u8(TAG_REAL);
+                self.u64(x.to_bits());
+            }
+            E(n) => {
+                self.u8(TAG_EAGER);
+                self.u8(n);
+            }
+            // a channel is saved with what is queued in it
+            Chan(n) => {
+                let queue = chan::queue(n).ok_or_else(||
+                    format!("Chan#{} doesn't exist!", n))?;
+                self.u8(TAG_CHAN);
+                self.u32(queue.len() as u32);
+                for term in queue {
+                    self.term(term)?;
+                }
+            }
+            // a supercombinator is saved with its body
+            Super(n) => {
+                let (arity,body) = lift::get(n).ok_or_else(||
+                    format!("Super#{} doesn't exist!", n))?;
+                self.u8(TAG_SUPER);
+                self.string(&lift::name(n));
+                self.u32(arity as u32);
+                self.term(body)?;
+            }
+            Arg(n) => {
+                self.u8(TAG_ARG);
+                self.u32(n as u32);
+            }
+            // the code itself is saved with the task
+            Code(pc) => {
+                self.u8(TAG_CODE);
+                self.u32(pc);
+            }
+            _ => {
+                return Err(format!("{:?} can't be saved!", *node));
+            }
+        }
+        Ok(())
+    }
+    pub fn finish(mut self) -> Result<Vec<u8>,String> {
+        let records = mem::take(&mut self.out);
+        // nodes found while writing a node are written after it
+        let mut i = 0;
+        while i < self.nodes.len() {
+            self.node(self.nodes[i])?;
+            i += 1;
+        }
+        let nodes = mem::take(&mut self.out);
+        self.out.extend_from_slice(self.magic);
+        self.u32(VERSION);
+        self.u32(term::const_count() as u32);
+        self.u32(self.symbs.len() as u32);
+        for symb in mem::take(&mut self.symbs) {
+            self.string(&symb.str());
+        }
+        self.u32(self.nodes.len() as u32);
+        self.out.extend(nodes);
+        self.u32(self.records);
+        self.out.extend(records);
+        Ok(self.out)
+    }
+}
+
+pub struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    symbs: Vec<Symb>,
+    nodes: Vec<TermRef>,
+    // nodes for chans (with their queue) and supercombinators (with
+    // name, arity and body), they only exist once `register` is called
+    chans: Vec<(usize,Vec<TermRef>)>,
+    supers: Vec<(usize,String,usize,TermRef)>,
+    records: u32,
+}
+
+impl<'a> Reader<'a> {
+    // Rebuild the term graph into `arena`, the records are read after.
+    // Nothing outside the arena is touched until `register`, so a bad
+    // file leaves no chan or supercombinator behind.
+    pub fn new(data: &'a [u8], arena: &mut Arena) -> Result<Reader<'a>,String> {
+        Reader::with_magic(data, IMAGE_MAGIC, arena)
+    }
+    fn with_magic(data: &'a [u8], magic: &[u8],
+                  arena: &mut Arena) -> Result<Reader<'a>,String> {
+        let mut reader = Reader {
+            data,
+            pos: 0,
+            symbs: Vec::new(),
+            nodes: Vec::new(),
+            chans: Vec::new(),
+            supers: Vec::new(),
+            records: 0,
+        };
+        if reader.bytes(magic.len())? != magic {
+            return Err(if magic == COMPILED_MAGIC {
+                "Not a compiled file!"
+            } else {
+                "Not an image file!"
+            }.to_string());
+        }
+        if reader.u32()? != VERSION {
+            return Err("Unsupported image version!".to_string());
+        }
+        if reader.u32()? as usize != term::const_count() {
+            return Err("Image made by a different build!".to_string());
+        }
+        for _ in 0..reader.u32()? {
+            let name = reader.string()?;
+            reader.symbs.push(Symb::from_string(name));
+        }
+        // every node gets its place first, so references can point
+        // forward and cycles come back as cycles
+        let count = reader.u32()? as usize;
+        if count > (data.len() - reader.pos) / NODE_MIN {
+            return Err("Image is truncated!".to_string());
+        }
+        if count.div_ceil(heap::page_size()) > heap::free_slots() {
+            return Err("Image doesn't fit in the heap!".to_string());
+        }
+        for _ in 0..count {
+            reader.nodes.push(arena.alloc(I));
+        }
+        for i in 0..count {
+            let term = match reader.u8()? {
+                TAG_APP => App(reader.term()?, reader.term()?),
+                TAG_LAM => Lam(reader.symb()?, reader.term()?),
+                TAG_VAR => Var(reader.symb()?),
+                TAG_INT => DInt(reader.u64()? as i64),
+                TAG_CHAR => {
+                    DChar(char::from_u32(reader.u32()?)
+                        .ok_or("Bad char in image!")?)
+                }
+                TAG_REAL => DReal(f64::from_bits(reader.u64()?)),
+                TAG_EAGER => E(reader.u8()?),
+                TAG_CHAN => {
+                    let mut queue = Vec::new();
+                    for _ in 0..reader.u32()? {
+                        queue.push(reader.term()?);
+                    }
+                    reader.chans.push((i, queue));
+                    continue;
+                }
+                TAG_SUPER => {
+                    let name = reader.string()?;
+                    let arity = reader.u32()? as usize;
+                    let body = reader.term()?;
+                    reader.supers.push((i, name, arity, body));
+                    continue;
+                }
+                TAG_ARG => Arg(reader.u32()? as usize),
+                TAG_CODE => Code(reader.u32()?),
+                tag => { return Err(format!("Bad node tag {}!", tag)); }
+            };
+            reader.set(i, term);
+        }
+        reader.records = reader.u32()?;
+        Ok(reader)
+    }
+    // Create the chans and supercombinators of the image, once all of
+    // it has been read.
+    pub fn register(&mut self) {
+        for (i, queue) in mem::take(&mut self.chans) {
+            let id = chan::new_chan();
+            self.set(i, Chan(id));
+            for term in queue {
+                chan::send(id, term);
+            }
+        }
+        let mut supers = Vec::new();
+        for (i, name, arity, body) in mem::take(&mut self.supers) {
+            let id = lift::new_super(name, arity, body);
+            self.set(i, Super(id));
+            supers.push(id);
+        }
+        // code isn't saved, it is compiled again from the bodies
+        if compile::backend() == Backend::Bytecode {
+            for id in supers {
+                gmachine::compile_super(id);
+            }
+        }
+    }
+    fn set(&self, i: usize, term: Term) {
+        // the arena isn't visible to anyone else yet
+        unsafe { self.nodes[i].set(term); }
+    }
+    pub fn records(&self) -> u32 {
+        self.records
+    }
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8],String> {
+        if self.pos + n > self.data.len() {
+            return Err("Image is truncated!".to_string());
+        }
+        let bytes = &self.data[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(bytes)
+    }
+    pub fn u8(&mut self) -> Result<u8,String> {
+        Ok(self.bytes(1)?[0])
+    }
+    pub fn u32(&mut self) -> Result<u32,String> {
+        let mut buf = [0; 4];
+        buf.copy_from_slice(self.bytes(4)?);
+        Ok(u32::from_le_bytes(buf))
+    }
+    pub fn u64(&mut self) -> Result<u64,String> {
+        let mut buf = [0; 8];
+        buf.copy_from_slice(self.bytes(8)?);
+        Ok(u64::from_le_bytes(buf))
+    }
+    pub fn string(&mut self) -> Result<String,String> {
+        let len = self.u32()? as usize;
+        String::from_utf8(self.bytes(len)?.to_vec())
+            .map_err(|_| "Bad string in image!".to_string())
+    }
+    pub fn symb(&mut self) -> Result<Symb,String> {
+        let id = self.u32()? as usize;
+        self.symbs.get(id).copied()
+            .ok_or_else(|| "Bad symbol in image!".to_string())
+    }
+    pub fn term(&mut self) -> Result<TermRef,String> {
+        let id = self.u32()?;
+        if id & CONST_BIT != 0 {
+            term::const_ref((id & !CONST_BIT) as usize)
+        } else {
+            self.nodes.get(id as usize).copied()
+        }.ok_or_else(|| "Bad reference in image!".to_string())
+    }
+}
+
+pub const RECORD_DICT: u8 = b'D';
+pub const RECORD_TASK: u8 = b'T';
+
+pub fn save_image(path: &str) -> Result<usize,String> {
+    let mut writer = Writer::new();
+    let count = symbol::dict_save(&mut writer)?;
+    let data = writer.finish()?;
+    fs::write(path, data).map_err(|err| err.to_string())?;
+    Ok(count)
+}
+
+// Definitions in the image replace the ones with the same name.
+pub fn load_image(path: &str) -> Result<usize,String> {
+    load_dict(path, IMAGE_MAGIC)
+}
+
+fn load_dict(path: &str, magic: &[u8]) -> Result<usize,String> {
+    let data = fs::read(path).map_err(|err| err.to_string())?;
+    let mut arena = Arena::new();
+    let mut reader = Reader::with_magic(&data, magic, &mut arena)?;
+    let mut dict = Vec::new();
+    for _ in 0..reader.records() {
+        match reader.u8()? {
+            RECORD_DICT => { dict.push(symbol::dict_load(&mut reader)?); }
+            tag => { return Err(format!("Bad record tag {}!", tag)); }
+        }
+    }
+    reader.register();
+    arena.into_perm();
+    let count = dict.len();
+    symbol::dict_insert(dict);
+    Ok(count)
+}
+
+// A compiled file (.nrmc) is an image with the definitions of one
+// source file, loading it skips parsing and compiling them.
+pub fn save_compiled(defs: Vec<(Symb,String)>,
+                     path: &str) -> Result<usize,String> {
+    let mut writer = Writer::with_magic(COMPILED_MAGIC);
+    let count = symbol::compile_save(defs, &mut writer)?;
+    let data = writer.finish()?;
+    fs::write(path, data).map_err(|err| err.to_string())?;
+    Ok(count)
+}
+
+pub fn load_compiled(path: &str) -> Result<usize,String> {
+    load_dict(path, COMPILED_MAGIC)
+}
+
+pub fn save_task(task: &Task) -> Result<Vec<u8>,String> {
+    let mut writer = Writer::new();
+    writer.record(RECORD_TASK);
+    eval::task_save(task, &mut writer)?;
+    writer.finish()
+}
+
+// The task keeps running, the file gets a copy of it.
+pub fn checkpoint(id: usize, path: &str) -> Result<(),String> {
+    let data = task::checkpoint(id)?;
+    fs::write(path, data).map_err(|err| err.to_string())
+}
+
+// Start a checkpointed task again, returns its new id.
+pub fn resume(path: &str) -> Result<usize,String> {
+    let data = fs::read(path).map_err(|err| err.to_string())?;
+    let mut arena = Arena::new();
+    let mut reader = Reader::new(&data, &mut arena)?;
+    if reader.records() != 1 || reader.u8()? != RECORD_TASK {
+        return Err("Not a task checkpoint!".to_string());
+    }
+    let task = eval::task_load(&mut reader)?;
+    reader.register();
+    let id = task.id();
+    arena.into_nursery(|| task::send_task(task));
+    Ok(id)
+}
+
+#[test]
+pub fn image_sharing_test() {
+    let x = Symb::new("x");
+    let shared = term::int_term(1 << 40);
+    let lam = alloc!(Lam(x, shared));
+    let root = alloc!(App(lam, shared));
+    let mut writer = Writer::new();
+    writer.record(RECORD_DICT);
+    writer.term(root).unwrap();
+    let data = writer.finish().unwrap();
+    let mut arena = Arena::new();
+    let mut reader = Reader::new(&data, &mut arena).unwrap();
+    assert_eq!(reader.records(), 1);
+    assert_eq!(reader.u8().unwrap(), RECORD_DICT);
+    let loaded = reader.term().unwrap();
+    assert_ne!(loaded.index(), root.index());
+    if let App(t1,t2) = *loaded {
+        if let Lam(y,t) = *t1 {
+            assert!(y == x);
+            assert_eq!(t.index(), t2.index());
+            assert_eq!(*t2, DInt(1 << 40));
+            return;
+        }
+    }
+    panic!("image changed the shape of the term!");
+}
+
+#[test]
+pub fn compiled_file_test() {
+    let defs = vec![
+        (Symb::new("nrmc_inc"), "(\\n. + n 1)".to_string()),
+        (Symb::new("nrmc_twice"), "(\\n. nrmc_inc (nrmc_inc n))".to_string()),
+    ];
+    let mut writer = Writer::with_magic(COMPILED_MAGIC);
+    assert_eq!(symbol::compile_save(defs, &mut writer).unwrap(), 2);
+    let data = writer.finish().unwrap();
+    let mut arena = Arena::new();
+    // an image and a compiled file don't load as each other
+    let err = Reader::new(&data, &mut arena).err();
+    assert_eq!(err.as_deref(), Some("Not an image file!"));
+    let image = Writer::new().finish().unwrap();
+    let err = Reader::with_magic(&image, COMPILED_MAGIC, &mut arena).err();
+    assert_eq!(err.as_deref(), Some("Not a compiled file!"));
+    let mut reader = Reader::with_magic(&data, COMPILED_MAGIC, &mut arena).unwrap();
+    assert_eq!(reader.records(), 2);
+    let mut names = Vec::new();
+    for _ in 0..reader.records() {
+        assert_eq!(reader.u8().unwrap(), RECORD_DICT);
+        let (key,_) = symbol::dict_load(&mut reader).unwrap();
+        names.push(key.str());
+    }
+    assert_eq!(names, vec!["nrmc_inc", "nrmc_twice"]);
+}
+
+#[test]
+pub fn image_bad_count_test() {
+    let mut writer = Writer::new();
+    writer.record(RECORD_DICT);
+    writer.term(term::int_term(1 << 40)).unwrap();
+    let mut data = writer.finish().unwrap();
+    // the node count follows the header and the empty symbol table
+    let pos = IMAGE_MAGIC.len() + 12;
+    data[pos..pos + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+    let mut arena = Arena::new();
+    let err = Reader::new(&data, &mut arena).err();
+    assert_eq!(err.as_deref(), Some("Image is truncated!"));
+}
+
+#[test]
+pub fn truncated_resume_test() {
+    let chan = chan::new_chan();
+    chan::send(chan, term::int_term(7));
+    let comb = lift::new_super("resume_cut".to_string(), 0, term::int_term(1));
+    let task = Task::new(alloc!(App(alloc!(Super(comb)), alloc!(Chan(chan)))));
+    let data = save_task(&task).unwrap();
+    let combs = || lift::ids().into_iter()
+        .filter(|id| lift::name(*id) == "resume_cut").count();
+    let chans = chan::ids();
+    let path = std::env::temp_dir().join("nrm_truncated_resume.ckpt");
+    let path = path.to_str().unwrap();
+    // the graph is complete in the first, the task record is cut short
+    for cut in [data.len() - 1, data.len() / 2] {
+        fs::write(path, &data[..cut]).unwrap();
+        assert_eq!(resume(path).err().as_deref(), Some("Image is truncated!"));
+    }
+    let _ = fs::remove_file(path);
+    assert_eq!(chan::ids(), chans);
+    assert_eq!(combs(), 1);
+}
+
+#[test]
+pub fn bytecode_checkpoint_test() {
+    let (name,term) = compile::test_programs().into_iter()
+        .find(|(name,_)| name == "fib_12").unwrap();
+    let lifted = lift::lift(&name, term);
+    for id in lift::reachable(lifted) {
+        gmachine::compile_super(id);
+    }
+    let (expected,_) = compile::reduce_steps(lifted);
+    // stopped deep inside the code of fib
+    let mut task = Task::new(lifted);
+    assert!(task.eval(500).is_none());
+    let data = save_task(&task).unwrap();
+    let mut arena = Arena::new();
+    let mut reader = Reader::new(&data, &mut arena).unwrap();
+    assert_eq!(reader.u8().unwrap(), RECORD_TASK);
+    let mut resumed = eval::task_load(&mut reader).unwrap();
+    reader.register();
+    let ret = loop {
+        if let Some(ret) = resumed.eval(1000) {
+            break ret;
+        }
+    };
+    assert_eq!(format!("{:?}", *ret), expected);
+}
@@ -10,6 +10,8 @@ use crate::infer::Type::*;
 use crate::infer::Expr::*;
 use crate::symbol::Symb;
 use crate::util;
+use crate::term::{Term, TermRef};
+use crate::compile;
 
 lazy_static::lazy_static! {
     static ref NAME_LIST: Vec<&'static str> = vec![
@@ -28,6 +30,8 @@ pub enum Expr {
     Lam(Symb,ExprRef),
     App(ExprRef,ExprRef),
     LetIn(Symb,ExprRef,ExprRef),
+    // a constant with a scheme in `builtin`
+    Builtin(Term),
 }
 
 impl Deref for ExprRef {
@@ -45,10 +49,12 @@ impl ExprRef {
 
 #[derive(Clone)]
 struct TypeRef(Rc<Type>);
-pub enum Type {
+enum Type {
     Const(Symb),
     TVar(Symb),
     Arrow(TypeRef,TypeRef),
+    // a constructor taking one type, Chan a
+    Con(Symb,TypeRef),
 }
 
 impl Deref for TypeRef {
@@ -73,6 +79,11 @@ impl Debug for Type {
             TVar(x) => {
                 write!(f,"{:?}",x)?;
             }
+            Con(c,t) => {
+                write!(f,"({:?} ",c)?;
+                t.fmt(f)?;
+                write!(f,")")?;
+            }
             Arrow(t1,t2) => {
                 write!(f,"(")?;
                 t1.fmt(f)?;
@@ -112,6 +123,9 @@ impl TypeRef {
                     stack.push(t2.clone());
                     stack.push(t1.clone());
                 }
+                Con(_,t) => {
+                    stack.push(t.clone());
+                }
             }
         }
         result
@@ -123,7 +137,9 @@ impl TypeRef {
                 if let Some(t) = sub.get(&x)
                 { t.clone().subst(sub) } else { self.clone() }
             Arrow(t1,t2) =>
-                TypeRef(Rc::new(Arrow(t1.subst(sub),t2.subst(sub))))
+                TypeRef(Rc::new(Arrow(t1.subst(sub),t2.subst(sub)))),
+            Con(c,t) =>
+                TypeRef(Rc::new(Con(*c,t.subst(sub)))),
         }
     }
     fn occur_check(&self, x: Symb) -> bool {
@@ -145,9 +161,7 @@ impl Debug for Scheme {
                 write!(f," ")?;
                 x.fmt(f)?;
             }
-            write!(f,".")?;
-        } else {
-            write!(f,":")?;
+            write!(f,". ")?;
         }
         self.1.fmt(f)?;
         Ok(())
@@ -185,6 +199,9 @@ impl Scheme {
                     stack.push(t2.clone());
                     stack.push(t1.clone());
                 }
+                Con(_,t) => {
+                    stack.push(t.clone());
+                }
             }
         }
         let newvars = name.iter()
@@ -318,6 +335,7 @@ impl Constraints {
             let ref ty1 = t1.subst(&map);
             let ref ty2 = t2.subst(&map);
             match (ty1.deref(),ty2.deref()) {
+                (TVar(x),TVar(y)) if x == y => {}
                 (TVar(x),_) => {
                     if ty2.occur_check(*x) {
                         return Err("Occur check failed!".to_string());
@@ -343,6 +361,9 @@ impl Constraints {
                     self.cons.push((a1.clone(),b1.clone()));
                     self.cons.push((a2.clone(),b2.clone()));
                 }
+                (Con(c1,a),Con(c2,b)) if c1 == c2 => {
+                    self.cons.push((a.clone(),b.clone()));
+                }
                 (a,b) => {
                     return Err(format!("Can't unify {:?} and {:?}!",a,b))
                 }
@@ -392,7 +413,7 @@ impl Infer {
     fn instantiate(&mut self, sc: &Scheme) -> TypeRef {
         let mut sub = HashMap::new();
         let len = sc.0.len();
-        for i in 1..len {
+        for i in 0..len {
             sub.insert(sc.0[i], self.newvar());
         }
         sc.1.subst(&sub)
@@ -402,11 +423,15 @@ impl Infer {
             LitInt(_) => {
                 Ok(TypeRef::constant("Int"))
             }
+            Builtin(con) => {
+                let sc = builtin(con).ok_or(format!("{:?} has no type!", con))?;
+                Ok(self.instantiate(&sc))
+            }
             Var(x) => {
                 if let Some(sc) = self.env.lookup(*x).cloned() {
                     Ok(self.instantiate(&sc))
                 } else {
-                    Err("Variable not in the environment!".to_string())
+                    Err(format!("Variable {:?} not in the environment!", x))
                 }
                 
             }
@@ -444,6 +469,175 @@ impl Infer {
         let sc = self.generalize(&ty.subst(&sub));
         Ok(sc)
     }
+    // mutually recursive definitions are monomorphic in their bodies
+    fn infer_group(&mut self, group: &[(Symb,ExprRef)]) -> Result<Vec<Scheme>,String> {
+        let mark = self.env.backup();
+        let mut tys = Vec::new();
+        for (x,_) in group {
+            let ty = self.newvar();
+            self.env.update(*x, &Scheme::new(&ty));
+            tys.push(ty);
+        }
+        for ((_,exp),ty) in group.iter().zip(&tys) {
+            let ty2 = self.infer(exp)?;
+            self.cons.unify(ty, &ty2);
+        }
+        let sub = self.cons.solve()?;
+        self.env.recover(mark);
+        Ok(tys.iter().map(|ty| self.generalize(&ty.subst(&sub))).collect())
+    }
+    fn translate(&mut self, term: TermRef) -> Result<ExprRef,String> {
+        let exp = match *term {
+            Term::Var(x) => Var(x),
+            Term::Lam(x,t) => Lam(x,self.translate(t)?),
+            Term::App(t1,t2) => App(self.translate(t1)?,self.translate(t2)?),
+            Term::DInt(n) => LitInt(n),
+            con => Builtin(con),
+        };
+        Ok(ExprRef::new(exp))
+    }
+}
+
+fn tvar(x: &str) -> TypeRef {
+    TypeRef::new(TVar(Symb::new(x)))
+}
+
+fn chan(ty: TypeRef) -> TypeRef {
+    TypeRef::new(Con(Symb::new("Chan"),ty))
+}
+
+// t1 -> t2 -> .. -> tn
+fn arrows(tys: Vec<TypeRef>) -> TypeRef {
+    let mut tys = tys.into_iter().rev();
+    let mut ty = tys.next().unwrap();
+    for t in tys {
+        ty = TypeRef::new(Arrow(t,ty));
+    }
+    ty
+}
+
+fn scheme(vars: &str, tys: Vec<TypeRef>) -> Scheme {
+    Scheme(vars.split_whitespace().map(Symb::new).collect(),arrows(tys))
+}
+
+// (a1 -> .. -> an -> r) -> a1 -> .. -> an -> r
+fn eager(n: usize) -> Scheme {
+    let vars: Vec<String> = (0..=n).map(|i| format!("a{}", i)).collect();
+    let mut tys: Vec<TypeRef> = vars.iter().map(|x| tvar(x)).collect();
+    tys.insert(0, arrows(tys.clone()));
+    scheme(&vars.join(" "), tys)
+}
+
+fn builtin(term: &Term) -> Option<Scheme> {
+    let (a,b,c,d) = (tvar("a"),tvar("b"),tvar("c"),tvar("d"));
+    let int = TypeRef::constant("Int");
+    let bool = TypeRef::constant("Bool");
+    let sc = match term {
+        Term::DBool(_) => scheme("", vec![bool]),
+        Term::DChar(_) => scheme("", vec![TypeRef::constant("Char")]),
+        Term::DReal(_) => scheme("", vec![TypeRef::constant("Real")]),
+        Term::I => scheme("a", vec![a.clone(),a]),
+        Term::K => scheme("a b", vec![a.clone(),b,a]),
+        Term::S => scheme("a b c", vec![arrows(vec![a.clone(),b.clone(),c.clone()]),
+                                        arrows(vec![a.clone(),b]),a,c]),
+        Term::B => scheme("a b c", vec![arrows(vec![b.clone(),c.clone()]),
+                                        arrows(vec![a.clone(),b]),a,c]),
+        Term::C => scheme("a b c", vec![arrows(vec![a.clone(),b.clone(),c.clone()]),
+                                        b,a,c]),
+        Term::Sp => scheme("a b c d", vec![arrows(vec![b.clone(),c.clone(),d.clone()]),
+                                           arrows(vec![a.clone(),b]),
+                                           arrows(vec![a.clone(),c]),a,d]),
+        Term::Bs => scheme("a b c d", vec![arrows(vec![c.clone(),d.clone()]),
+                                           arrows(vec![b.clone(),c]),
+                                           arrows(vec![a.clone(),b]),a,d]),
+        Term::Cp => scheme("a b c d", vec![arrows(vec![b.clone(),c.clone(),d.clone()]),
+                                           arrows(vec![a.clone(),b]),c,a,d]),
+        Term::E1 => eager(1),
+        Term::E2 => eager(2),
+        Term::E3 => eager(3),
+        Term::E4 => eager(4),
+        Term::E(n) => eager(*n as usize),
+        Term::AddI | Term::SubI | Term::MulI | Term::DivI =>
+            scheme("", vec![int.clone(),int.clone(),int]),
+        // Not compares like EqlI
+        Term::GrtI | Term::LssI | Term::EqlI | Term::Not =>
+            scheme("", vec![int.clone(),int,bool]),
+        Term::And | Term::Or => scheme("", vec![bool.clone(),bool.clone(),bool]),
+        Term::Ifte => scheme("a", vec![bool,a.clone(),a.clone(),a]),
+        Term::Seq | Term::Par => scheme("a b", vec![a,b.clone(),b]),
+        // newChan f = f c, send c x k = k, recv c f = f x
+        Term::NewChan => scheme("a b", vec![arrows(vec![chan(a),b.clone()]),b]),
+        Term::SendChan => scheme("a b", vec![chan(a.clone()),a,b.clone(),b]),
+        Term::RecvChan => scheme("a b", vec![chan(a.clone()),arrows(vec![a,b.clone()]),b]),
+        _ => return None,
+    };
+    Some(sc)
+}
+
+// Orders the definitions `term` uses so each group of mutually recursive
+// ones comes after everything it uses (Tarjan).
+struct Groups<'a> {
+    defs: &'a dyn Fn(Symb) -> Option<TermRef>,
+    index: HashMap<Symb,usize>,
+    low: HashMap<Symb,usize>,
+    stack: Vec<Symb>,
+    groups: Vec<Vec<Symb>>,
+}
+
+impl Groups<'_> {
+    fn uses(&self, x: Symb) -> Vec<Symb> {
+        let def = (self.defs)(x).unwrap();
+        compile::free_vars(def).into_iter()
+            .filter(|y| (self.defs)(*y).is_some()).collect()
+    }
+    fn visit(&mut self, x: Symb) {
+        let i = self.index.len();
+        self.index.insert(x, i);
+        self.low.insert(x, i);
+        self.stack.push(x);
+        for y in self.uses(x) {
+            if !self.index.contains_key(&y) {
+                self.visit(y);
+                let low = self.low[&x].min(self.low[&y]);
+                self.low.insert(x, low);
+            } else if self.stack.contains(&y) {
+                let low = self.low[&x].min(self.index[&y]);
+                self.low.insert(x, low);
+            }
+        }
+        if self.low[&x] == i {
+            let at = self.stack.iter().rposition(|y| *y == x).unwrap();
+            self.groups.push(self.stack.split_off(at));
+        }
+    }
+}
+
+// The type of `term`, with `defs` giving the parsed definitions.
+pub fn type_of(term: TermRef, defs: &dyn Fn(Symb) -> Option<TermRef>) -> Result<String,String> {
+    let mut groups = Groups {
+        defs, index: HashMap::new(), low: HashMap::new(),
+        stack: Vec::new(), groups: Vec::new(),
+    };
+    for x in compile::free_vars(term) {
+        if defs(x).is_some() && !groups.index.contains_key(&x) {
+            groups.visit(x);
+        }
+    }
+    let mut inf = Infer::new();
+    for group in groups.groups {
+        let mut exps = Vec::new();
+        for x in group {
+            exps.push((x,inf.translate(defs(x).unwrap())?));
+        }
+        let scs = inf.infer_group(&exps)
+            .map_err(|msg| format!("in {:?}: {}", exps[0].0, msg))?;
+        for ((x,_),sc) in exps.iter().zip(scs) {
+            inf.env.update(*x, &sc);
+        }
+    }
+    let exp = inf.translate(term)?;
+    let sc = inf.infer_top(&exp)?;
+    Ok(format!("{:?}", sc))
 }
 
 macro_rules! letin {
@@ -491,4 +685,33 @@ pub fn infer_test() -> Result<(),String> {
     let sc2 = inf.infer_top(&e2)?;
     println!("type: {:?}",sc2);
     Ok(())
-}
\ No newline at end of file
+}
+
+#[test]
+pub fn type_of_test() {
+    let parse = |text| crate::parser::parse_term(text).unwrap();
+    let fact = parse("(\\n. if (= n 0) 1 (* n (fact (- n 1))))");
+    let even = parse("(\\n. if (= n 0) (= 0 0) (odd (- n 1)))");
+    let odd = parse("(\\n. if (= n 0) (= 0 1) (even (- n 1)))");
+    let defs = |x: Symb| {
+        if x == Symb::new("fact") { Some(fact) }
+        else if x == Symb::new("even") { Some(even) }
+        else if x == Symb::new("odd") { Some(odd) }
+        else { None }
+    };
+    let cases = vec![
+        ("(\\x. x)", "∀ a. (a -> a)"),
+        ("(+ 1)", "(Int -> Int)"),
+        ("(\\f.\\x. f (f x))", "∀ a. ((a -> a) -> a -> a)"),
+        ("(\\x.\\y. seq x y)", "∀ a b. (a -> b -> b)"),
+        ("fact", "(Int -> Int)"),
+        ("(odd 3)", "Bool"),
+        ("(newChan (\\c. send c 1 (recv c (\\x. + x 1))))", "Int"),
+    ];
+    for (text,expected) in cases {
+        assert_eq!(type_of(parse(text), &defs), Ok(expected.to_string()), "{}", text);
+    }
+    assert!(type_of(parse("(+ 1 (\\x. x))"), &defs).is_err());
+    assert!(type_of(parse("(\\x. x x)"), &defs).is_err());
+    assert!(type_of(parse("(newChan (\\c. send c 1 (send c true 0)))"), &defs).is_err());
+}
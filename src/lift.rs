@@ -0,0 +1,327 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::term::*;
+use crate::term::Term::*;
+use crate::symbol::Symb;
+use crate::heap::Roots;
+use crate::compile;
+use crate::gmachine;
+use crate::gmachine::{Instr, Code};
+
+// A supercombinator takes a fixed number of arguments and is reduced
+// by copying its body with `Arg(i)` replaced by the i-th argument.
+// Definitions are lambda lifted into them: every group of nested
+// lambdas becomes one, taking the variables it uses from the outside
+// as extra arguments in front of its own.
+pub struct SuperComb {
+    name: String,
+    arity: usize,
+    body: TermRef,
+    // with the bytecode backend
+    code: Option<Code>,
+}
+
+// A reclaimed slot is empty until a new supercombinator takes it.
+struct SuperTable {
+    combs: Vec<Option<SuperComb>>,
+    free: Vec<usize>,
+}
+
+impl SuperTable {
+    fn get(&self, id: usize) -> Option<&SuperComb> {
+        self.combs.get(id)?.as_ref()
+    }
+    fn get_mut(&mut self, id: usize) -> Option<&mut SuperComb> {
+        self.combs.get_mut(id)?.as_mut()
+    }
+}
+
+lazy_static::lazy_static! {
+    // read on every reduction, written by the compiler and the gc
+    static ref SUPER_TABLE: RwLock<SuperTable> =
+        RwLock::new(SuperTable { combs: Vec::new(), free: Vec::new() });
+    // made by the command running on the main thread, nothing has to
+    // point at them before it is done
+    static ref PINNED: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+}
+
+// live supercombinators before the gc looks for unreachable ones
+static RECLAIM_AT: AtomicUsize = AtomicUsize::new(256);
+
+pub fn new_super(name: String, arity: usize, body: TermRef) -> usize {
+    let mut table = SUPER_TABLE.write().unwrap();
+    let comb = Some(SuperComb { name, arity, body, code: None });
+    let id = if let Some(id) = table.free.pop() {
+        table.combs[id] = comb;
+        id
+    } else {
+        table.combs.push(comb);
+        table.combs.len() - 1
+    };
+    PINNED.lock().unwrap().push(id);
+    id
+}
+
+// The command is done, its supercombinators are kept by the terms
+// using them from now on.
+pub fn unpin() {
+    PINNED.lock().unwrap().clear();
+}
+
+pub fn get(id: usize) -> Option<(usize,TermRef)> {
+    let table = SUPER_TABLE.read().unwrap();
+    table.get(id).map(|comb| (comb.arity, comb.body))
+}
+
+// What the evaluator needs to reduce it.
+pub fn entry(id: usize) -> Option<(usize,TermRef,Option<Code>)> {
+    let table = SUPER_TABLE.read().unwrap();
+    table.get(id).map(|comb| (comb.arity, comb.body, comb.code.clone()))
+}
+
+pub fn code(id: usize) -> Option<Code> {
+    SUPER_TABLE.read().unwrap().get(id)?.code.clone()
+}
+
+pub fn set_body(id: usize, body: TermRef) {
+    if let Some(comb) = SUPER_TABLE.write().unwrap().get_mut(id) {
+        comb.body = body;
+    }
+}
+
+pub fn set_code(id: usize, code: Vec<Instr>) {
+    if let Some(comb) = SUPER_TABLE.write().unwrap().get_mut(id) {
+        comb.code = Some(Arc::new(code));
+    }
+}
+
+pub fn name(id: usize) -> String {
+    let table = SUPER_TABLE.read().unwrap();
+    table.get(id).map_or_else(|| format!("#{}", id), |comb| comb.name.clone())
+}
+
+pub fn ids() -> HashSet<usize> {
+    let table = SUPER_TABLE.read().unwrap();
+    table.combs.iter().enumerate()
+        .filter_map(|(id,comb)| comb.as_ref().map(|_| id))
+        .collect()
+}
+
+struct Lifter {
+    name: String,
+    count: usize,
+}
+
+impl Lifter {
+    fn new_name(&mut self) -> String {
+        self.count += 1;
+        format!("{}.{}", self.name, self.count)
+    }
+    // \x1..xn. body => Super applied to the free variables in `env`
+    fn lift_lam(&mut self, term: TermRef, env: &[Symb]) -> TermRef {
+        let mut free: Vec<Symb> = Vec::new();
+        for x in env {
+            if !free.contains(x) && compile::is_free_in(*x, term) {
+                free.push(*x);
+            }
+        }
+        let mut params = free.clone();
+        let mut body = term;
+        while let Lam(x,t) = *body {
+            params.push(x);
+            body = t;
+        }
+        let name = self.new_name();
+        let body = self.lift(body, &params);
+        let mut root = alloc!(Super(new_super(name, params.len(), body)));
+        for x in free {
+            let i = env.iter().rposition(|y| *y == x).unwrap();
+            root = app!(root,alloc!(Arg(i)));
+        }
+        root
+    }
+    fn lift(&mut self, term: TermRef, env: &[Symb]) -> TermRef {
+        match *term {
+            Var(x) => {
+                if let Some(i) = env.iter().rposition(|y| *y == x) {
+                    alloc!(Arg(i))
+                } else {
+                    // a definition in the dictionary
+                    term
+                }
+            }
+            App(t1,t2) => {
+                app!(self.lift(t1, env),self.lift(t2, env))
+            }
+            Lam(_,_) => { self.lift_lam(term, env) }
+            _ => { term }
+        }
+    }
+}
+
+// The definition itself is the outermost supercombinator, one with no
+// parameters stays an expression.
+pub fn lift(name: &str, term: TermRef) -> TermRef {
+    let mut lifter = Lifter { name: name.to_string(), count: 0 };
+    let mut params = Vec::new();
+    let mut body = term;
+    while let Lam(x,t) = *body {
+        params.push(x);
+        body = t;
+    }
+    let body = lifter.lift(body, &params);
+    if params.is_empty() {
+        body
+    } else {
+        alloc!(Super(new_super(name.to_string(), params.len(), body)))
+    }
+}
+
+// Copy the parts of `body` that hold arguments, share the rest. The
+// arguments are in stack order, the first one last.
+pub fn instantiate(body: TermRef, args: &[TermRef]) -> TermRef {
+    match *body {
+        Arg(i) => { args[args.len() - 1 - i] }
+        App(t1,t2) => {
+            let u1 = instantiate(t1, args);
+            let u2 = instantiate(t2, args);
+            if u1.index() == t1.index() && u2.index() == t2.index() {
+                body
+            } else {
+                app!(u1,u2)
+            }
+        }
+        _ => { body }
+    }
+}
+
+// The supercombinators `term` uses, each once.
+pub fn reachable(term: TermRef) -> Vec<usize> {
+    let mut found = Vec::new();
+    let mut seen = HashSet::new();
+    let mut stack = vec![term];
+    while let Some(term) = stack.pop() {
+        match *term {
+            App(t1,t2) => { stack.push(t2); stack.push(t1); }
+            Super(id) if seen.insert(id) => {
+                if let Some((_,body)) = get(id) {
+                    found.push(id);
+                    stack.push(body);
+                }
+            }
+            _ => {}
+        }
+    }
+    found
+}
+
+pub fn show_supers(term: TermRef) {
+    for id in reachable(term) {
+        let (arity,body) = get(id).unwrap();
+        let args: Vec<String> = (0..arity)
+            .map(|i| format!(" Arg#{}", i)).collect();
+        println!("${}{} = {:?}", name(id), args.concat(), *body);
+    }
+}
+
+// Roots must not look at the table, it is locked for the whole pass.
+pub fn super_roots(roots: &mut dyn Roots) {
+    let mut table = SUPER_TABLE.write().unwrap();
+    for comb in table.combs.iter_mut().flatten() {
+        let name = format_args!("${}", comb.name);
+        comb.body = roots.root(comb.body, &name);
+        // the globals in the code are part of it too
+        comb.code = comb.code.as_ref()
+            .map(|code| gmachine::root_code(code, roots, &name));
+    }
+}
+
+// Walks everything reachable from the roots for the supercombinators
+// in use, once the table has grown enough since the last time. The
+// others are reclaimed, their ids are given out again.
+pub struct Reclaimer {
+    live: HashSet<usize>,
+    seen: HashSet<u32>,
+}
+
+impl Roots for Reclaimer {
+    fn root(&mut self, term: TermRef, _: &dyn fmt::Display) -> TermRef {
+        self.walk(term);
+        term
+    }
+}
+
+impl Reclaimer {
+    pub fn new() -> Option<Reclaimer> {
+        let table = SUPER_TABLE.read().unwrap();
+        let count = table.combs.len() - table.free.len();
+        drop(table);
+        if count < RECLAIM_AT.load(Ordering::Relaxed) {
+            return None;
+        }
+        let mut reclaimer = Reclaimer { live: HashSet::new(), seen: HashSet::new() };
+        let pinned = PINNED.lock().unwrap().clone();
+        for id in pinned {
+            reclaimer.found(id);
+        }
+        Some(reclaimer)
+    }
+    fn found(&mut self, id: usize) {
+        if !self.live.insert(id) {
+            return;
+        }
+        let (body,code) = match SUPER_TABLE.read().unwrap().get(id) {
+            Some(comb) => (comb.body, comb.code.clone()),
+            None => { return; }
+        };
+        self.walk(body);
+        for instr in code.iter().flat_map(|code| code.iter()) {
+            if let Instr::PushGlobal(t) = instr {
+                self.walk(*t);
+            }
+        }
+    }
+    fn walk(&mut self, root: TermRef) {
+        let mut stack = vec![root];
+        while let Some(term) = stack.pop() {
+            if !self.seen.insert(term.index()) {
+                continue;
+            }
+            match *term {
+                App(t1,t2) => { stack.push(t1); stack.push(t2); }
+                Lam(_,t) | Link(_,t) | Array(_,t) => { stack.push(t); }
+                Super(id) => { self.found(id); }
+                _ => {}
+            }
+        }
+    }
+    // returns how many were reclaimed
+    pub fn reclaim(self) -> usize {
+        let mut table = SUPER_TABLE.write().unwrap();
+        let mut reclaimed = 0;
+        for id in 0..table.combs.len() {
+            if table.combs[id].is_some() && !self.live.contains(&id) {
+                table.combs[id] = None;
+                table.free.push(id);
+                reclaimed += 1;
+            }
+        }
+        RECLAIM_AT.store(256.max(self.live.len() * 2), Ordering::Relaxed);
+        reclaimed
+    }
+}
+
+#[test]
+pub fn lift_compare_test() {
+    for (name,term) in compile::test_programs() {
+        let ski = compile::optimize(compile::compile_ski(term));
+        let lifted = lift(&name, term);
+        let (ret1,steps1) = compile::reduce_steps(ski);
+        let (ret2,steps2) = compile::reduce_steps(lifted);
+        assert_eq!(ret1, ret2, "{}", name);
+        assert!(steps2 <= steps1, "{}", name);
+    }
+}
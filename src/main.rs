@@ -8,16 +8,24 @@ mod symbol;
 mod parser;
 mod eval;
 mod compile;
+mod peval;
+mod strict;
+mod lift;
+mod gmachine;
 mod task;
+mod spark;
+mod chan;
 mod infer;
+mod verify;
+mod image;
 
 
 use parser::*;
+use compile::{Abstraction, Backend, Stage};
 
 extern crate lazy_static;
 extern crate regex;
 
-use std::process;
 use std::fs;
 
 use rustyline::{Editor, Result};
@@ -38,8 +46,47 @@ impl Validator for InputValidator {
     }
 }
 
+extern "C" fn on_sigint(_: libc::c_int) {
+    task::interrupt();
+}
+
+// returns the image to start from, if any
+fn read_args() -> Option<String> {
+    let mut image = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let text = args.next();
+        if arg == "--image" && text.is_some() {
+            image = text;
+            continue;
+        }
+        let value = text.and_then(|v| v.parse::<usize>().ok());
+        match (arg.as_str(), value) {
+            ("--workers", Some(n)) if n > 0 => { task::set_worker_max(n); }
+            ("--timeslice", Some(n)) if n > 0 && n <= task::TIMESLICE_MAX => {
+                task::set_timeslice(n);
+            }
+            ("--page-size", Some(n)) if n > 0 && n <= heap::PAGE_MAX => {
+                heap::set_page_size(n);
+            }
+            ("--nursery", Some(n)) if n > 0 && n <= heap::NURSERY_MAX => {
+                heap::set_nursery_size(n);
+            }
+            ("--watermark", Some(n)) if n > 0 => { heap::set_watermark(n); }
+            ("--max-heap", Some(n)) => { heap::set_max_heap(n << 20); }
+            ("--mark-slice", Some(n)) if n > 0 => { heap::set_mark_slice(n); }
+            _ => { println!("Ignored argument {}!", arg); }
+        }
+    }
+    image
+}
+
 fn main() {
+    let image = read_args();
     task::thread_init();
+    unsafe {
+        libc::signal(libc::SIGINT, on_sigint as extern "C" fn(libc::c_int) as libc::sighandler_t);
+    }
 
     let h = InputValidator {
         brackets: MatchingBracketValidator::new(),
@@ -49,7 +96,11 @@ fn main() {
     if rl.load_history("history.txt").is_err() {
         println!("No previous history.");
     }
-    command_line(":load test.nrm".to_string());
+    if let Some(path) = image {
+        command_line(format!(":load-image {}", path));
+    } else {
+        command_line(":load test.nrm".to_string());
+    }
     loop {
         let readline = rl.readline("> ");
         match readline {
@@ -57,11 +108,13 @@ fn main() {
                 rl.add_history_entry(line.as_str());
                 let input = String::from(line);
                 let input = input.to_string();
-                command_line(input);
+                if !command_line(input) {
+                    break;
+                }
             },
             Err(ReadlineError::Interrupted) => {
-                println!("CTRL-C");
-                process::exit(1);
+                // drop the current line and prompt again
+                continue;
             },
             Err(ReadlineError::Eof) => {
                 println!("CTRL-D");
@@ -78,19 +131,191 @@ fn main() {
     task::thread_exit();
 }
 
-fn command_line(input: String) {
+fn set_option(name: &str, value: &str) {
+    let mut par = parser::Parser::new(value.to_string());
+    match name {
+        "verify" => {
+            if let Some(flag) = read_switch(&mut par) {
+                verify::set_verify(flag);
+            } else {
+                println!("Expected on or off!");
+            }
+        }
+        "hash-cons" => {
+            if let Some(flag) = read_switch(&mut par) {
+                symbol::set_hash_cons(flag);
+            } else {
+                println!("Expected on or off!");
+            }
+        }
+        "inline" => {
+            if let Some(flag) = read_switch(&mut par) {
+                peval::set_inline(flag);
+            } else {
+                println!("Expected on or off!");
+            }
+        }
+        "abstraction" => {
+            match value {
+                "turner" => compile::set_abstraction(Abstraction::Turner),
+                "kiselyov" => compile::set_abstraction(Abstraction::Kiselyov),
+                _ => { println!("Expected turner or kiselyov!"); }
+            }
+        }
+        "backend" => {
+            match value {
+                "ski" => compile::set_backend(Backend::Combinators),
+                "super" => compile::set_backend(Backend::Supercombinators),
+                "bytecode" => compile::set_backend(Backend::Bytecode),
+                _ => { println!("Expected ski, super or bytecode!"); }
+            }
+        }
+        "optimize" | "opt" => {
+            match value.parse() {
+                Ok(level) if level <= 2 => compile::set_opt_level(level),
+                _ => { println!("Expected 0, 1 or 2!"); }
+            }
+        }
+        "stages" => {
+            let mut stages = Vec::new();
+            for name in value.split([' ', ',']).filter(|n| !n.is_empty()) {
+                match name {
+                    "all" => stages.extend(compile::STAGES),
+                    "none" => {}
+                    "parsed" => stages.push(Stage::Parsed),
+                    "simplified" => stages.push(Stage::Simplified),
+                    "compiled" => stages.push(Stage::Compiled),
+                    "optimized" => stages.push(Stage::Optimized),
+                    "lifted" => stages.push(Stage::Lifted),
+                    "task" => stages.push(Stage::Task),
+                    _ => {
+                        println!("Unknown stage {}!", name);
+                        return;
+                    }
+                }
+            }
+            compile::set_shown(stages);
+        }
+        "verify-steps" => {
+            if let Ok(n) = value.parse() {
+                verify::set_verify_steps(n);
+            } else {
+                println!("Expected a number of steps!");
+            }
+        }
+        _ => {
+            println!("Unknown option {}!", name);
+        }
+    }
+}
+
+// Everything `:set` can change.
+struct Options {
+    verify: bool,
+    verify_steps: usize,
+    hash_cons: bool,
+    inline: bool,
+    abstraction: Abstraction,
+    backend: Backend,
+    opt_level: usize,
+    shown: Vec<Stage>,
+}
+
+impl Options {
+    fn current() -> Options {
+        Options {
+            verify: verify::enabled(),
+            verify_steps: verify::verify_steps(),
+            hash_cons: symbol::hash_consing(),
+            inline: peval::inlining(),
+            abstraction: compile::abstraction(),
+            backend: compile::backend(),
+            opt_level: compile::opt_level(),
+            shown: compile::shown_stages(),
+        }
+    }
+    fn restore(self) {
+        verify::set_verify(self.verify);
+        verify::set_verify_steps(self.verify_steps);
+        symbol::set_hash_cons(self.hash_cons);
+        peval::set_inline(self.inline);
+        compile::set_abstraction(self.abstraction);
+        compile::set_backend(self.backend);
+        compile::set_opt_level(self.opt_level);
+        compile::set_shown(self.shown);
+    }
+}
+
+// Only the definitions of the file are compiled, the options it sets
+// apply to them and are put back after, anything else in it is left
+// for loading the source.
+fn compile_file(src: &str, out: &str) -> std::result::Result<usize,String> {
+    let text = fs::read_to_string(src)
+        .map_err(|_| format!("Can't read file {}!", src))?;
+    let options = Options::current();
+    let mut defs = Vec::new();
+    for command in text.split(";;") {
+        let mut par = parser::Parser::new(command.trim().to_string());
+        match parser::read_command(&mut par) {
+            Some(Command::Define(symb,input)) |
+            Some(Command::Update(symb,input)) => { defs.push((symb,input)); }
+            Some(Command::Set(name,value)) => { set_option(&name, &value); }
+            _ => {}
+        }
+    }
+    let result = image::save_compiled(defs, out);
+    options.restore();
+    result
+}
+
+// returns false when the repl should exit
+fn command_line(input: String) -> bool {
     let input = input.trim().to_string();
-    if input == "" { return }
+    if input.is_empty() { return true }
     //println!("cmd {}",input);
     let mut par = parser::Parser::new(input);
     if let Some(command) = parser::read_command(&mut par) {
         match command {
             Command::Quit => {
-                process::exit(1);
+                return false;
             }
             Command::Dict => {
                 symbol::show_dict();
             }
+            Command::Gc => {
+                heap::force_gc();
+                heap::show_heap();
+            }
+            Command::GcVerbose(flag) => {
+                heap::set_gc_verbose(flag);
+            }
+            Command::Set(name,value) => {
+                set_option(&name, &value);
+            }
+            Command::ShowBytecode(symb) => {
+                if let Some(term) = symbol::lookup(symb) {
+                    if !gmachine::show_bytecode(term) {
+                        println!("{:?} isn't compiled to bytecode!", symb);
+                    }
+                } else {
+                    println!("definition doesn't exist!");
+                }
+            }
+            Command::Strictness(symb) => {
+                symbol::show_strictness(symb);
+            }
+            Command::Explain(term) => {
+                compile::explain(term);
+            }
+            Command::Type(term) => {
+                match symbol::type_of(term) {
+                    Ok(ty) => println!("{:?} : {}", *term, ty),
+                    Err(msg) => println!("type error: {}", msg),
+                }
+            }
+            Command::Heap => {
+                heap::show_heap();
+            }
             Command::Define(symb,term) => {
                 symbol::define(symb,term);
             }
@@ -100,31 +325,114 @@ fn command_line(input: String) {
             Command::Delete(symb) => {
                 symbol::delete(symb);
             }
+            Command::Load(path) if path.ends_with(".nrmc") => {
+                match image::load_compiled(&path) {
+                    Ok(n) => println!("{} definitions loaded from {}.", n, &path),
+                    Err(msg) => println!("(:load) {}", msg),
+                }
+            }
+            Command::Compile(src,out) => {
+                match compile_file(&src, &out) {
+                    Ok(n) => println!("{} definitions compiled to {}.", n, &out),
+                    Err(msg) => println!("(:compile) {}", msg),
+                }
+            }
             Command::Load(path) => {
-                let text = fs::read_to_string(&path).unwrap();
-                for command in text.split(";;") {
-                    command_line(command.to_string());  
+                if let Ok(text) = fs::read_to_string(&path) {
+                    for command in text.split(";;") {
+                        if !command_line(command.to_string()) {
+                            return false;
+                        }
+                    }
+                    println!("load:{} finished.", &path);
+                } else {
+                    println!("Can't read file {}!", &path);
+                }
+            }
+            Command::Spawn(term) => {
+                let term = if compile::opt_level() >= 1 {
+                    symbol::simplify(term)
+                } else { term };
+                let term = symbol::link(compile::compile("spawn", term));
+                let task = eval::Task::new(term);
+                println!("task #{} spawned.", task.id());
+                heap::hand_over(|| task::send_task(task));
+            }
+            Command::Checkpoint(id,path) => {
+                match image::checkpoint(id, &path) {
+                    Ok(()) => println!("task #{} saved to {}.", id, &path),
+                    Err(msg) => println!("(:checkpoint) {}", msg),
+                }
+            }
+            Command::Resume(path) => {
+                match image::resume(&path) {
+                    Ok(id) => println!("task #{} resumed from {}.", id, &path),
+                    Err(msg) => println!("(:resume) {}", msg),
+                }
+            }
+            Command::SaveImage(path) => {
+                match image::save_image(&path) {
+                    Ok(n) => println!("{} definitions saved to {}.", n, &path),
+                    Err(msg) => println!("(:save-image) {}", msg),
+                }
+            }
+            Command::LoadImage(path) => {
+                match image::load_image(&path) {
+                    Ok(n) => println!("{} definitions loaded from {}.", n, &path),
+                    Err(msg) => println!("(:load-image) {}", msg),
                 }
-                println!("load:{} finished.", &path);
             }
             Command::Repl(term) => {
-                println!("Parsed: {:?}", *term);
-                let compiled = compile::compile_ski(term);
-                println!("Compiled: {:?}", *compiled);
-                let optimized = compile::optimize(compiled);
-                println!("Optimized: {:?}", *optimized);
-                let mut task = eval::Task::new(optimized);
-                println!("Task: {:?}", task);
-                if let Some(ret) = task.eval(256) {
-                    println!("{:?}", *ret);
+                if compile::shown(Stage::Parsed) {
+                    println!("Parsed: {:?}", *term);
+                }
+                let simplified = if compile::opt_level() >= 1 {
+                    symbol::simplify(term)
+                } else { term };
+                if compile::shown(Stage::Simplified)
+                    && simplified.index() != term.index() {
+                    println!("Simplified: {:?}", *simplified);
+                }
+                let term = simplified;
+                let optimized = if compile::backend() == Backend::Combinators {
+                    let compiled = compile::bracket(term);
+                    if compile::shown(Stage::Compiled) {
+                        println!("Compiled: {:?} [{} nodes]", *compiled,
+                            compile::size(compiled));
+                    }
+                    let optimized = if compile::opt_level() >= 1 {
+                        compile::optimize(compiled)
+                    } else { compiled };
+                    if compile::shown(Stage::Optimized) {
+                        println!("Optimized: {:?} [{} nodes]", *optimized,
+                            compile::size(optimized));
+                    }
+                    optimized
                 } else {
-                    task::send_task(task);
+                    let lifted = compile::compile("repl", term);
+                    if compile::shown(Stage::Lifted) {
+                        println!("Lifted: {:?}", *lifted);
+                        lift::show_supers(lifted);
+                    }
+                    lifted
+                };
+                let task = eval::Task::new(symbol::link(optimized));
+                if compile::shown(Stage::Task) {
+                    println!("Task: {:?}", task);
+                }
+                match task::run_foreground(task) {
+                    Ok(ret) => { println!("{}", ret); }
+                    Err(msg) => { println!("{}", msg); }
                 }
             }
         }
     } else {
         println!("Can't parse command!");
     }
+    lift::unpin();
+    // nothing the workers see points to what is left of the command
+    heap::hand_over(|| {});
+    true
 }
 
 
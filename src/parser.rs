@@ -104,6 +104,8 @@ lazy_static::lazy_static! {
         r"^[_A-Za-z][_A-Za-z0-9]*").unwrap();
     static ref PATH_RE: Regex = Regex::new(
         r"^.+").unwrap();
+    static ref OPTION_RE: Regex = Regex::new(
+        r"^[a-z][a-z0-9-]*").unwrap();
 }
 
 pub fn read_int(par: &mut Parser) -> Option<i64> {
@@ -215,6 +217,11 @@ pub fn read_const_func(par: &mut Parser) -> Option<TermRef> {
         const_parser!(app!(C_E2,C_AND),"and"),
         const_parser!(app!(C_E2,C_OR),"or"),
         const_parser!(app!(C_E1,C_IFTE),"if"),
+        const_parser!(app!(C_E1,C_SEQ),"seq"),
+        const_parser!(C_PAR,"par"),
+        const_parser!(C_NEWCHAN,"newChan"),
+        const_parser!(app!(C_E1,C_SEND),"send"),
+        const_parser!(app!(C_E1,C_RECV),"recv"),
     ])
 }
 
@@ -225,12 +232,31 @@ pub fn read_path(par: &mut Parser) -> Option<String> {
     })
 }
 
+pub fn read_switch(par: &mut Parser) -> Option<bool> {
+    par.try_read_many(vec![
+        |p| { p.read_string("on")?; Some(true) },
+        |p| { p.read_string("off")?; Some(false) },
+    ])
+}
+
 pub enum Command {
-    Quit,Dict,
+    Quit,Dict,Gc,Heap,
+    GcVerbose(bool),
+    Set(String,String),
+    ShowBytecode(Symb),
+    Strictness(Symb),
+    Explain(TermRef),
+    Type(TermRef),
     Define(Symb,String),
     Update(Symb,String),
     Delete(Symb),
     Load(String),
+    Compile(String,String),
+    Spawn(TermRef),
+    Checkpoint(usize,String),
+    Resume(String),
+    SaveImage(String),
+    LoadImage(String),
     Repl(TermRef),
 }
 
@@ -259,6 +285,70 @@ pub fn read_command(par: &mut Parser) -> Option<Command> {
             p.is_end()?;
             Some(Command::Dict)
         },
+        |p|{
+            p.read_string(":gc")?;
+            p.skip_space();
+            p.is_end()?;
+            Some(Command::Gc)
+        },
+        |p|{
+            p.read_string(":gc")?;
+            p.skip_space();
+            p.read_string("verbose")?;
+            p.skip_space();
+            let flag = read_switch(p)?;
+            p.skip_space();
+            p.is_end()?;
+            Some(Command::GcVerbose(flag))
+        },
+        |p|{
+            p.read_string(":set")?;
+            p.skip_space();
+            let name = p.read_regex(&OPTION_RE)?.to_string();
+            p.skip_space();
+            let value = p.get_rest();
+            Some(Command::Set(name,value.trim().to_string()))
+        },
+        |p|{
+            p.read_string(":show")?;
+            p.skip_space();
+            p.read_string("bytecode")?;
+            p.skip_space();
+            let symb = read_symb(p)?;
+            p.skip_space();
+            p.is_end()?;
+            Some(Command::ShowBytecode(symb))
+        },
+        |p|{
+            p.read_string(":strictness")?;
+            p.skip_space();
+            let symb = read_symb(p)?;
+            p.skip_space();
+            p.is_end()?;
+            Some(Command::Strictness(symb))
+        },
+        |p|{
+            p.read_string(":explain")?;
+            p.skip_space();
+            let term = read_app_list(p)?;
+            p.skip_space();
+            p.is_end()?;
+            Some(Command::Explain(term))
+        },
+        |p|{
+            p.read_string(":type")?;
+            p.skip_space();
+            let term = read_app_list(p)?;
+            p.skip_space();
+            p.is_end()?;
+            Some(Command::Type(term))
+        },
+        |p|{
+            p.read_string(":heap")?;
+            p.skip_space();
+            p.is_end()?;
+            Some(Command::Heap)
+        },
         |p|{
             p.read_string(":define")?;
             p.skip_space();
@@ -283,6 +373,62 @@ pub fn read_command(par: &mut Parser) -> Option<Command> {
             p.is_end()?;
             Some(Command::Delete(symb))
         },
+        |p|{
+            p.read_string(":spawn")?;
+            p.skip_space();
+            let term = read_app_list(p)?;
+            p.skip_space();
+            p.is_end()?;
+            Some(Command::Spawn(term))
+        },
+        |p|{
+            p.read_string(":checkpoint")?;
+            p.skip_space();
+            let id = read_int(p)? as usize;
+            p.skip_space();
+            let path = read_path(p)?;
+            p.skip_space();
+            p.is_end()?;
+            Some(Command::Checkpoint(id,path))
+        },
+        |p|{
+            p.read_string(":resume")?;
+            p.skip_space();
+            let path = read_path(p)?;
+            p.skip_space();
+            p.is_end()?;
+            Some(Command::Resume(path))
+        },
+        |p|{
+            p.read_string(":save-image")?;
+            p.skip_space();
+            let path = read_path(p)?;
+            p.skip_space();
+            p.is_end()?;
+            Some(Command::SaveImage(path))
+        },
+        |p|{
+            p.read_string(":load-image")?;
+            p.skip_space();
+            let path = read_path(p)?;
+            p.skip_space();
+            p.is_end()?;
+            Some(Command::LoadImage(path))
+        },
+        |p|{
+            p.read_string(":compile")?;
+            p.skip_space();
+            let path = read_path(p)?;
+            // the output defaults to the source with .nrmc
+            if let Some((src,out)) = path.split_once(" -o ") {
+                Some(Command::Compile(src.trim().to_string(),
+                                      out.trim().to_string()))
+            } else {
+                let src = path.trim();
+                let stem = src.strip_suffix(".nrm").unwrap_or(src);
+                Some(Command::Compile(src.to_string(), format!("{}.nrmc", stem)))
+            }
+        },
         |p|{
             p.read_string(":load")?;
             p.skip_space();
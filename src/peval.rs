@@ -0,0 +1,195 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::term::*;
+use crate::term::Term::*;
+use crate::symbol::Symb;
+use crate::compile;
+
+// Simplifies a parsed term before it is compiled: primitives on
+// literals are folded, `if` on a known boolean picks its branch and
+// redexes are reduced when that can't duplicate any work. Small
+// definitions can be inlined too, which only pays off with the rest.
+static INLINE: AtomicBool = AtomicBool::new(false);
+// nodes of the parsed definition
+const INLINE_SIZE: usize = 16;
+// reductions and inlinings for one term
+const FUEL: usize = 10000;
+
+pub fn set_inline(flag: bool) {
+    INLINE.store(flag, Ordering::Relaxed);
+}
+
+pub fn inlining() -> bool {
+    INLINE.load(Ordering::Relaxed)
+}
+
+// The value of `op a b`, unless it would fail at run time.
+fn fold(op: Term, a: i64, b: i64) -> Option<TermRef> {
+    match op {
+        AddI => Some(i!(a.checked_add(b)?)),
+        SubI => Some(i!(a.checked_sub(b)?)),
+        MulI => Some(i!(a.checked_mul(b)?)),
+        DivI => Some(i!(a.checked_div(b)?)),
+        GrtI => Some(b!(a > b)),
+        LssI => Some(b!(a < b)),
+        EqlI => Some(b!(a == b)),
+        _ => None,
+    }
+}
+
+// How many times `x` is free in `term`, and if one is under a lambda.
+fn occurs(x: Symb, term: TermRef) -> (usize,bool) {
+    match *term {
+        Var(y) if y == x => (1, false),
+        Lam(y,t) if y != x => {
+            let (n,_) = occurs(x, t);
+            (n, n > 0)
+        }
+        App(t1,t2) => {
+            let (n1,l1) = occurs(x, t1);
+            let (n2,l2) = occurs(x, t2);
+            (n1 + n2, l1 || l2)
+        }
+        _ => (0, false),
+    }
+}
+
+// body[x := arg], None if a lambda in `body` would capture `arg`.
+fn subst(x: Symb, arg: TermRef, body: TermRef) -> Option<TermRef> {
+    match *body {
+        Var(y) if y == x => Some(arg),
+        Lam(y,t) if y != x && compile::is_free_in(x, t) => {
+            if compile::is_free_in(y, arg) {
+                return None;
+            }
+            Some(lam!(y,subst(x, arg, t)?))
+        }
+        App(t1,t2) => {
+            Some(app!(subst(x, arg, t1)?,subst(x, arg, t2)?))
+        }
+        _ => Some(body),
+    }
+}
+
+struct Peval<'a> {
+    // the parsed definition of a global, if it may be inlined
+    defs: &'a dyn Fn(Symb) -> Option<TermRef>,
+    inline: bool,
+    inlining: Vec<Symb>,
+    fuel: usize,
+}
+
+impl Peval<'_> {
+    fn simplify(&mut self, term: TermRef, bound: &mut Vec<Symb>) -> TermRef {
+        match *term {
+            Var(x) if !bound.contains(&x) => {
+                self.inline(x, bound).unwrap_or(term)
+            }
+            Lam(x,t) => {
+                bound.push(x);
+                let u = self.simplify(t, bound);
+                bound.pop();
+                if u.index() == t.index() { term } else { lam!(x,u) }
+            }
+            App(t1,t2) => {
+                let (u1,u2) = (self.simplify(t1, bound),self.simplify(t2, bound));
+                if let Some(reduced) = self.reduce(u1, u2, bound) {
+                    reduced
+                } else if u1.index() == t1.index() && u2.index() == t2.index() {
+                    term
+                } else {
+                    app!(u1,u2)
+                }
+            }
+            _ => term,
+        }
+    }
+    fn inline(&mut self, x: Symb, bound: &[Symb]) -> Option<TermRef> {
+        if !self.inline || self.fuel == 0
+            || self.inlining.contains(&x) {
+            return None;
+        }
+        let def = (self.defs)(x)?;
+        if compile::size(def) > INLINE_SIZE
+            || compile::free_vars(def).iter().any(|y| bound.contains(y)) {
+            return None;
+        }
+        self.fuel -= 1;
+        self.inlining.push(x);
+        let inlined = self.simplify(def, &mut Vec::new());
+        self.inlining.pop();
+        Some(inlined)
+    }
+    // `u1 u2` with both already simplified
+    fn reduce(&mut self, u1: TermRef, u2: TermRef,
+              bound: &mut Vec<Symb>) -> Option<TermRef> {
+        match *u1 {
+            // E2 op a b
+            App(t11,a) => {
+                if let (App(e,op),DInt(a),DInt(b)) = (*t11,*a,*u2) {
+                    if let E2 = *e {
+                        return fold(*op, a, b);
+                    }
+                }
+                // E1 Ifte c t e
+                if let App(t111,c) = *t11 {
+                    if let (App(e,op),DBool(p)) = (*t111,*c) {
+                        if let (E1,Ifte) = (*e,*op) {
+                            return Some(if p { a } else { u2 });
+                        }
+                    }
+                }
+                None
+            }
+            Lam(x,body) if self.fuel > 0 => {
+                let (n,under_lam) = occurs(x, body);
+                let atomic = !matches!(*u2, App(_,_) | Lam(_,_));
+                if n == 0 {
+                    self.fuel -= 1;
+                    Some(body)
+                } else if atomic || (n == 1 && !under_lam) {
+                    let reduced = subst(x, u2, body)?;
+                    self.fuel -= 1;
+                    Some(self.simplify(reduced, bound))
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+// Globals are inlined only if `inline` is set, usually from `inlining()`.
+pub fn simplify(term: TermRef, inline: bool,
+                defs: &dyn Fn(Symb) -> Option<TermRef>) -> TermRef {
+    let mut peval = Peval { defs, inline, inlining: Vec::new(), fuel: FUEL };
+    peval.simplify(term, &mut Vec::new())
+}
+
+#[test]
+pub fn simplify_test() {
+    let square = crate::parser::parse_term("(\\x. * x x)").unwrap();
+    let defs = |x: Symb| {
+        if x == Symb::new("square") { Some(square) } else { None }
+    };
+    let cases = vec![
+        ("(+ 1 2)", "3"),
+        ("(if (= 0 0) 5 6)", "5"),
+        ("(\\n. if (< 3 2) n (- n 1))", "λ n. E2 SubI n 1"),
+        ("(\\x. (\\y. + y 1) x)", "λ x. E2 AddI x 1"),
+        ("((\\x. * x x) (+ 1 2))", "9"),
+        // would evaluate f x twice
+        ("(\\x. (\\y. + y y) (f x))", "λ x. λ y. E2 AddI y y (f x)"),
+        // the inner y would capture the argument
+        ("(\\y. (\\x.\\y. x) y)", "λ y. λ x y. x y"),
+        ("(/ 1 0)", "(E2 DivI 1 0)"),
+        ("(square 4)", "(square 4)"),
+    ];
+    for (text,expected) in cases {
+        let term = crate::parser::parse_term(text).unwrap();
+        assert_eq!(format!("{:?}", *simplify(term, false, &defs)), expected);
+    }
+    let term = crate::parser::parse_term("(square 4)").unwrap();
+    assert_eq!(format!("{:?}", *simplify(term, true, &defs)), "16");
+}
@@ -0,0 +1,186 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::heap;
+use crate::heap::Roots;
+use crate::term::TermRef;
+use crate::eval;
+use crate::eval::Task;
+use crate::task;
+
+// A spark is created by `par`, the node it replaces is overwritten
+// with `Spark(id)`, so every term sharing that node sees the result.
+// Once the spark is done the node is overwritten again with its value
+// and the entry is removed.
+pub enum SparkState {
+    Pending(TermRef),
+    // the term, the task evaluating it and the ones waiting for it
+    Running(TermRef,usize,Vec<Task>),
+}
+
+pub enum Demand {
+    Done,
+    Claim(TermRef),
+    Wait,
+}
+
+lazy_static::lazy_static! {
+    static ref SPARK_MAP: Mutex<HashMap<usize,(TermRef,SparkState)>> =
+                            Mutex::new(HashMap::new());
+}
+static SPARK_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+pub fn new_spark(node: TermRef, term: TermRef) -> usize {
+    let id = SPARK_COUNT.fetch_add(1, Ordering::SeqCst);
+    let mut map = SPARK_MAP.lock().unwrap();
+    map.insert(id, (node,SparkState::Pending(term)));
+    id
+}
+
+// The term of a spark nobody has started yet.
+pub fn pending(id: usize) -> Option<TermRef> {
+    let map = SPARK_MAP.lock().unwrap();
+    if let Some((_,SparkState::Pending(term))) = map.get(&id) {
+        Some(*term)
+    } else {
+        None
+    }
+}
+
+// The first task who demands a pending spark evaluates it.
+pub fn demand(id: usize, task: usize) -> Demand {
+    let mut map = SPARK_MAP.lock().unwrap();
+    if let Some((_,state)) = map.get_mut(&id) {
+        match state {
+            SparkState::Pending(term) => {
+                let term = *term;
+                *state = SparkState::Running(term, task, Vec::new());
+                Demand::Claim(term)
+            }
+            SparkState::Running(_,_,_) => { Demand::Wait }
+        }
+    } else {
+        // the node was updated after we read it
+        Demand::Done
+    }
+}
+
+pub fn fulfil(id: usize, value: TermRef) {
+    let old = {
+        let mut map = SPARK_MAP.lock().unwrap();
+        let old = map.remove(&id);
+        if let Some((node,_)) = old {
+            heap::term_update(node, *value);
+        }
+        old
+    };
+    if let Some((_,SparkState::Running(_,_,waiters))) = old {
+        for task in waiters {
+            task::send_task(task);
+        }
+    }
+}
+
+// The sparks claimed by a task that is gone are pending again, the
+// tasks waiting for them go back to the pool and demand them again.
+pub fn release(task: usize) {
+    let mut woken = Vec::new();
+    {
+        let mut map = SPARK_MAP.lock().unwrap();
+        for (_,state) in map.values_mut() {
+            if let SparkState::Running(term, owner, waiters) = state {
+                if *owner == task {
+                    woken.append(waiters);
+                    *state = SparkState::Pending(*term);
+                }
+            }
+        }
+    }
+    for task in woken {
+        task::send_task(task);
+    }
+}
+
+// Park a task until the spark is done.
+pub fn wait(id: usize, task: Task) {
+    let mut map = SPARK_MAP.lock().unwrap();
+    if let Some((_,SparkState::Running(_,_,waiters))) = map.get_mut(&id) {
+        waiters.push(task);
+    } else {
+        drop(map);
+        task::send_task(task);
+    }
+}
+
+// Take out a parked foreground task, so Ctrl-C can abort it.
+pub fn drop_foreground() -> Option<Task> {
+    let mut map = SPARK_MAP.lock().unwrap();
+    for (_,state) in map.values_mut() {
+        if let SparkState::Running(_,_,waiters) = state {
+            if let Some(i) = waiters.iter().position(|task| task.is_foreground()) {
+                return Some(waiters.remove(i));
+            }
+        }
+    }
+    None
+}
+
+pub fn spark_roots(roots: &mut dyn Roots) {
+    let mut map = SPARK_MAP.lock().unwrap();
+    for (id,(node,state)) in map.iter_mut() {
+        *node = roots.root(*node, &format_args!("spark#{}.node", id));
+        match state {
+            SparkState::Pending(term) => {
+                *term = roots.root(*term, &format_args!("spark#{}", id));
+            }
+            SparkState::Running(term,_,waiters) => {
+                *term = roots.root(*term, &format_args!("spark#{}", id));
+                for task in waiters {
+                    eval::task_roots(task, roots);
+                }
+            }
+        }
+    }
+}
+
+pub fn ids() -> HashSet<usize> {
+    SPARK_MAP.lock().unwrap().keys().copied().collect()
+}
+
+#[test]
+pub fn interrupted_spark_test() {
+    use crate::term::*;
+    use crate::term::Term::*;
+    // par x (+ x 1), the task claims x itself and is interrupted
+    let x = app!(C_E2, C_ADDI, i!(40), i!(1));
+    let mut owner = Task::new(app!(C_PAR, x, app!(C_E2, C_ADDI, x, i!(1))));
+    let id = loop {
+        assert!(owner.eval(1).is_none() && owner.take_blocked().is_none());
+        if let Spark(id) = *x {
+            break id;
+        }
+    };
+    while pending(id).is_some() {
+        assert!(owner.eval(1).is_none() && owner.take_blocked().is_none());
+    }
+    let mut waiter = Task::new(x);
+    assert!(waiter.eval(10).is_none());
+    assert_eq!(waiter.take_blocked(), Some(eval::Block::Spark(id)));
+    let waiter_id = waiter.id();
+    wait(id, waiter);
+    task::fail_task(owner, "Interrupted!".to_string());
+    assert!(pending(id).is_some());
+    let mut woken = None;
+    for task in task::drain_task() {
+        if task.id() == waiter_id {
+            woken = Some(task);
+        } else {
+            task::send_task(task);
+        }
+    }
+    let mut waiter = woken.expect("the waiter wasn't woken up!");
+    let ret = waiter.eval(100).expect("the waiter didn't finish!");
+    assert_eq!(*ret, DInt(41));
+    assert!(pending(id).is_none());
+}
@@ -0,0 +1,203 @@
+use std::collections::HashSet;
+
+use crate::term::*;
+use crate::term::Term::*;
+use crate::symbol::Symb;
+
+// Strictness analysis on the lambda term of a definition. A parameter
+// is strict when evaluating the body always evaluates it, then it is
+// evaluated once on entry through `E(n)` instead of being passed on
+// as a growing chain of unevaluated applications. Recursive calls
+// start out strict in everything and are weakened to a fixpoint.
+struct Analysis<'a> {
+    name: Option<Symb>,
+    // what is assumed of the definition itself
+    sig: Vec<bool>,
+    // what is known of the others
+    sigs: &'a dyn Fn(Symb) -> Option<Vec<bool>>,
+}
+
+impl Analysis<'_> {
+    // the variables surely evaluated when `term` is evaluated
+    fn demand(&self, term: TermRef, bound: &mut Vec<Symb>) -> HashSet<Symb> {
+        let mut args = Vec::new();
+        let mut head = term;
+        while let App(t1,t2) = *head {
+            args.push(t2);
+            head = t1;
+        }
+        args.reverse();
+        self.demand_app(head, &args, bound)
+    }
+    fn demand_all(&self, terms: &[TermRef],
+                  bound: &mut Vec<Symb>) -> HashSet<Symb> {
+        let mut vars = HashSet::new();
+        for term in terms {
+            vars.extend(self.demand(*term, bound));
+        }
+        vars
+    }
+    // a call to something with `sig`, nothing is evaluated while it
+    // lacks arguments
+    fn demand_call(&self, sig: &[bool], args: &[TermRef],
+                   bound: &mut Vec<Symb>) -> HashSet<Symb> {
+        let mut vars = HashSet::new();
+        if args.len() >= sig.len() {
+            for (arg,strict) in args.iter().zip(sig) {
+                if *strict {
+                    vars.extend(self.demand(*arg, bound));
+                }
+            }
+        }
+        vars
+    }
+    fn demand_app(&self, head: TermRef, args: &[TermRef],
+                  bound: &mut Vec<Symb>) -> HashSet<Symb> {
+        let eager = match *head {
+            E1 => 1, E2 => 2, E3 => 3, E4 => 4,
+            E(n) => n as usize,
+            _ => 0,
+        };
+        if eager > 0 {
+            if args.len() <= eager {
+                return HashSet::new();
+            }
+            let mut vars = self.demand_all(&args[1..=eager], bound);
+            vars.extend(self.demand_app(args[0], &args[1..], bound));
+            return vars;
+        }
+        match *head {
+            Var(x) if bound.contains(&x) => {
+                HashSet::from([x])
+            }
+            Var(x) if Some(x) == self.name => {
+                self.demand_call(&self.sig, args, bound)
+            }
+            Var(x) => {
+                let sig = (self.sigs)(x).unwrap_or_default();
+                // an unknown definition may not even be a function
+                if sig.is_empty() { HashSet::new() }
+                else { self.demand_call(&sig, args, bound) }
+            }
+            Lam(x,t) if !args.is_empty() => {
+                bound.push(x);
+                let mut vars = self.demand_app_spine(t, &args[1..], bound);
+                bound.pop();
+                if vars.remove(&x) {
+                    vars.extend(self.demand(args[0], bound));
+                }
+                vars
+            }
+            Ifte if args.len() >= 3 => {
+                let then = self.demand(args[1], bound);
+                let other = self.demand(args[2], bound);
+                let mut vars = self.demand(args[0], bound);
+                vars.extend(then.intersection(&other).copied());
+                vars
+            }
+            Seq | Par if args.len() >= 2 => {
+                self.demand(args[1], bound)
+            }
+            _ => HashSet::new(),
+        }
+    }
+    // `term` applied to `args`
+    fn demand_app_spine(&self, term: TermRef, args: &[TermRef],
+                        bound: &mut Vec<Symb>) -> HashSet<Symb> {
+        let mut all = args.to_vec();
+        let mut head = term;
+        while let App(t1,t2) = *head {
+            all.insert(0, t2);
+            head = t1;
+        }
+        self.demand_app(head, &all, bound)
+    }
+}
+
+pub fn params(term: TermRef) -> (Vec<Symb>,TermRef) {
+    let mut params = Vec::new();
+    let mut body = term;
+    while let Lam(x,t) = *body {
+        params.push(x);
+        body = t;
+    }
+    (params,body)
+}
+
+// Which parameters of the definition `name` are strict, a term with no
+// name can't call itself.
+pub fn analyze(name: Option<Symb>, term: TermRef,
+               sigs: &dyn Fn(Symb) -> Option<Vec<bool>>) -> Vec<bool> {
+    let (params,body) = params(term);
+    let mut sig = vec![true; params.len()];
+    loop {
+        let analysis = Analysis { name, sig: sig.clone(), sigs };
+        let vars = analysis.demand(body, &mut params.clone());
+        // a shadowed parameter is never the one used
+        let next: Vec<bool> = params.iter().enumerate()
+            .map(|(i,x)| vars.contains(x)
+                 && params.iter().rposition(|y| y == x) == Some(i))
+            .collect();
+        if next == sig {
+            return sig;
+        }
+        sig = next;
+    }
+}
+
+// \x1..xn. body => \x1..xn. E(k) (\s1..sk. body) s1..sk, where the si
+// are the strict ones.
+pub fn make_strict(term: TermRef, sig: &[bool]) -> TermRef {
+    let (params,body) = params(term);
+    let strict: Vec<Symb> = params.iter().zip(sig)
+        .filter(|(_,s)| **s).map(|(x,_)| *x).collect();
+    if strict.is_empty() {
+        return term;
+    }
+    let mut inner = body;
+    for x in strict.iter().rev() {
+        inner = lam!(*x,inner);
+    }
+    let mut body = app!(eager!(strict.len() as u8),inner);
+    for x in &strict {
+        body = app!(body,var!(*x));
+    }
+    for x in params.iter().rev() {
+        body = lam!(*x,body);
+    }
+    body
+}
+
+// name !x y for a definition strict in x
+pub fn show(name: Symb, params: &[Symb], sig: &[bool]) -> String {
+    let mut text = format!("{:?}", name);
+    for (x,strict) in params.iter().zip(sig) {
+        text += &format!(" {}{:?}", if *strict { "!" } else { "" }, x);
+    }
+    text
+}
+
+#[test]
+pub fn strictness_test() {
+    let sigs = |x: Symb| {
+        if x == Symb::new("strict_add") { Some(vec![true,true]) } else { None }
+    };
+    let cases = vec![
+        ("strict_sum", "(\\acc.\\n. if (= n 0) acc; \
+                         strict_sum (+ acc n) (- n 1))", vec![true,true]),
+        ("strict_const", "(\\x.\\y. x)", vec![true,false]),
+        ("strict_loop", "(\\x.\\y. strict_loop y x)", vec![true,true]),
+        ("strict_branch", "(\\c.\\x.\\y. if c x y)", vec![true,false,false]),
+        ("strict_lazy", "(\\x.\\f. f x)", vec![false,true]),
+        ("strict_call", "(\\x.\\y. strict_add y 1)", vec![false,true]),
+        ("strict_beta", "(\\x. (\\y. + y 1) x)", vec![true]),
+        ("strict_par", "(\\x.\\y. par x y)", vec![false,true]),
+    ];
+    for (name,text,expected) in cases {
+        let term = crate::parser::parse_term(text).unwrap();
+        assert_eq!(analyze(Some(Symb::new(name)), term, &sigs), expected, "{}", name);
+    }
+    let sum = crate::parser::parse_term("(\\acc.\\n. + acc n)").unwrap();
+    assert_eq!(format!("{:?}", *make_strict(sum, &[true,true])),
+        "λ acc n. E2 λ acc n. E2 AddI acc n acc n");
+}
@@ -2,6 +2,7 @@ use std::collections::HashSet;
 use std::fmt;
 use std::fmt::Debug;
 use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 extern crate rand;
 use rand::Rng;
@@ -9,16 +10,96 @@ use rand::Rng;
 use bimap::BiMap;
 use std::collections::HashMap;
 
-use crate::term;
-use crate::term::TermRef;
+use crate::term::{Term, TermRef};
+use crate::term::Term::*;
+use crate::heap;
+use crate::heap::Roots;
+use crate::image;
+use crate::image::{Reader, Writer};
 use crate::parser;
 use crate::compile;
+use crate::lift;
+use crate::peval;
+use crate::strict;
+use crate::gmachine;
+use crate::infer;
 
 lazy_static::lazy_static! {
     static ref SYMB_MAP: Mutex<BiMap<u32,String>> = 
                             Mutex::new(BiMap::new());
     pub static ref DICT_MAP: Mutex<HashMap<Symb,DictValue>> = 
                             Mutex::new(HashMap::new());
+    // one node per distinct compiled subterm, dictionary terms live in
+    // permanent pages so these don't move, they are rooted all the same
+    static ref CONS_TABLE: Mutex<HashMap<Shape,TermRef>> =
+                            Mutex::new(HashMap::new());
+    // the node linked references to a symbol go through, a Link to
+    // its definition or a Var while there is none
+    static ref LINK_CELLS: Mutex<HashMap<Symb,TermRef>> =
+                            Mutex::new(HashMap::new());
+}
+static HASH_CONS: AtomicBool = AtomicBool::new(false);
+
+// a node with its children already shared
+#[derive(PartialEq,Eq,Hash)]
+enum Shape {
+    App(u32,u32),
+    Lam(Symb,u32),
+    Var(Symb),
+    Int(i64),
+    Char(char),
+}
+
+pub fn set_hash_cons(flag: bool) {
+    HASH_CONS.store(flag, Ordering::Relaxed);
+}
+
+pub fn hash_consing() -> bool {
+    HASH_CONS.load(Ordering::Relaxed)
+}
+
+// A consed node is shared by every definition built from it, so it is
+// permanent and the evaluator never overwrites it in place.
+fn hash_cons(term: TermRef) -> TermRef {
+    heap::perm(|| cons(term))
+}
+
+fn cons(term: TermRef) -> TermRef {
+    // constants are shared already
+    if term.slot() == 0 {
+        return term;
+    }
+    let keep = |node: Term| if heap::is_perm(term) { term } else { alloc!(node) };
+    let (shape, node) = match *term {
+        App(t1,t2) => {
+            let (u1,u2) = (cons(t1),cons(t2));
+            let node = if u1.index() == t1.index()
+                       && u2.index() == t2.index() { keep(App(u1,u2)) }
+                       else { app!(u1,u2) };
+            (Shape::App(u1.index(),u2.index()), node)
+        }
+        Lam(x,t) => {
+            let u = cons(t);
+            let node = if u.index() == t.index() { keep(Lam(x,u)) }
+                       else { lam!(x,u) };
+            (Shape::Lam(x,u.index()), node)
+        }
+        Var(x) => (Shape::Var(x), keep(Var(x))),
+        DInt(n) => (Shape::Int(n), keep(DInt(n))),
+        DChar(c) => (Shape::Char(c), keep(DChar(c))),
+        _ => { return term; }
+    };
+    *CONS_TABLE.lock().unwrap().entry(shape).or_insert(node)
+}
+
+// The shape of a node that was moved anyway is stale, its entry is
+// dropped and the node consed again by the next definition.
+pub fn cons_roots(roots: &mut dyn Roots) {
+    let mut table = CONS_TABLE.lock().unwrap();
+    table.retain(|_,node| {
+        let moved = roots.root(*node, &format_args!("cons#{}", node.index()));
+        moved.index() == node.index()
+    });
 }
 
 #[derive(Eq,Clone,Copy,Hash)]
@@ -47,7 +128,10 @@ impl Symb {
             return Symb(rnd);
         }
     }
-    fn str(&self) -> String {
+    pub fn exists(&self) -> bool {
+        SYMB_MAP.lock().unwrap().contains_left(&self.0)
+    }
+    pub fn str(&self) -> String {
         let map = SYMB_MAP.lock().unwrap();
         if let Some(right) = map.get_by_left(&self.0) {
             return right.clone();
@@ -76,19 +160,137 @@ pub struct DictValue {
     parsed: TermRef,
     compiled: TermRef,
     linked: Option<TermRef>,
+    // the parameters it always evaluates and the names they have in
+    // the term that was analyzed, not saved
+    strict: Vec<bool>,
+    params: Vec<Symb>,
 }
 
 impl DictValue {
-    pub fn new(input: String) -> Option<DictValue> {
+    // a definition is kept in permanent pages
+    pub fn new(symb: Symb, input: String,
+               map: &HashMap<Symb,DictValue>) -> Option<DictValue> {
+        heap::perm(|| DictValue::build(symb, input, map))
+    }
+    fn build(symb: Symb, input: String,
+             map: &HashMap<Symb,DictValue>) -> Option<DictValue> {
         let text = input;
         let parsed = parser::parse_term(&text[..])?;
-        let compiled = compile::compile_ski(parsed);
-        let compiled = compile::optimize(compiled);
-        let linked = None;
-        // TODO related
-        let related = Vec::new();
-        Some(DictValue { related, text, parsed, compiled, linked })
+        // the old body of `symb` may still be in the map
+        let simplified = if compile::opt_level() >= 1 {
+            peval::simplify(parsed, peval::inlining(), &|x| if x == symb { None } else {
+                inline_source(map, x)
+            })
+        } else { parsed };
+        let strict = strict::analyze(Some(symb), simplified, &|x| strictness(map, x));
+        let (params,_) = strict::params(simplified);
+        let simplified = if compile::opt_level() >= 2 {
+            strict::make_strict(simplified, &strict)
+        } else { simplified };
+        let compiled = compile::compile(&symb.str(), simplified);
+        let compiled = if HASH_CONS.load(Ordering::Relaxed) {
+            hash_cons(compiled)
+        } else { compiled };
+        let linked = Some(link(compiled));
+        let related = compile::free_vars(parsed);
+        Some(DictValue { related, text, parsed, compiled, linked, strict, params })
+    }
+}
+
+// What `symb` may be replaced with, never a recursive definition.
+fn inline_source(map: &HashMap<Symb,DictValue>, symb: Symb) -> Option<TermRef> {
+    let value = map.get(&symb)?;
+    if value.related.contains(&symb) { None } else { Some(value.parsed) }
+}
+
+fn strictness(map: &HashMap<Symb,DictValue>, symb: Symb) -> Option<Vec<bool>> {
+    map.get(&symb).map(|value| value.strict.clone())
+}
+
+pub fn show_strictness(symb: Symb) {
+    let map = DICT_MAP.lock().unwrap();
+    if let Some(value) = map.get(&symb) {
+        println!("{}", strict::show(symb, &value.params, &value.strict));
+    } else {
+        println!("definition doesn't exist!");
+    }
+}
+
+pub fn simplify(term: TermRef) -> TermRef {
+    let map = DICT_MAP.lock().unwrap();
+    peval::simplify(term, peval::inlining(), &|x| inline_source(&map, x))
+}
+
+// The strict parameters of a term that isn't defined yet.
+pub fn strictness_of(term: TermRef) -> Vec<bool> {
+    let map = DICT_MAP.lock().unwrap();
+    strict::analyze(None, term, &|x| strictness(&map, x))
+}
+
+// The type of a term, definitions are typed as they were parsed.
+pub fn type_of(term: TermRef) -> Result<String,String> {
+    let map = DICT_MAP.lock().unwrap();
+    infer::type_of(term, &|x| map.get(&x).map(|v| v.parsed))
+}
+
+fn link_cell(symb: Symb) -> TermRef {
+    *LINK_CELLS.lock().unwrap().entry(symb)
+        .or_insert_with(|| heap::perm(|| var!(symb)))
+}
+
+// Every term linked to `symb` sees the new definition at once, the
+// cells are permanent so recursive definitions can point back at
+// themselves.
+fn set_link(symb: Symb, linked: Option<TermRef>) {
+    let cell = link_cell(symb);
+    let term = match linked {
+        Some(t) => Term::Link(symb, t),
+        None => Term::Var(symb),
+    };
+    heap::term_update(cell, term);
+}
+
+struct Linker {
+    // keeps shared subterms shared
+    done: HashMap<u32,TermRef>,
+}
+
+impl Linker {
+    fn link(&mut self, term: TermRef) -> TermRef {
+        if let Some(linked) = self.done.get(&term.index()) {
+            return *linked;
+        }
+        let linked = match *term {
+            // compiled code has no bound variables left
+            Var(x) => link_cell(x),
+            App(t1,t2) => {
+                let (u1,u2) = (self.link(t1),self.link(t2));
+                if u1.index() == t1.index() && u2.index() == t2.index() {
+                    term
+                } else {
+                    app!(u1,u2)
+                }
+            }
+            _ => term,
+        };
+        self.done.insert(term.index(), linked);
+        linked
+    }
+}
+
+// Replace the references to definitions in `term` and in the
+// supercombinators it uses by their link cells, so evaluating them
+// doesn't take the dictionary lock.
+pub fn link(term: TermRef) -> TermRef {
+    let mut linker = Linker { done: HashMap::new() };
+    for id in lift::reachable(term) {
+        let (_,body) = lift::get(id).unwrap();
+        lift::set_body(id, linker.link(body));
+        if lift::code(id).is_some() {
+            gmachine::compile_super(id);
+        }
     }
+    linker.link(term)
 }
 
 pub fn lookup(symb: Symb) -> Option<TermRef> {
@@ -100,10 +302,41 @@ pub fn lookup(symb: Symb) -> Option<TermRef> {
         Some(value.compiled)
     }
 }
+// The definitions using `symb`.
+fn dependents(map: &HashMap<Symb,DictValue>, symb: Symb) -> Vec<Symb> {
+    let mut users: Vec<Symb> = map.iter()
+        .filter(|(key, value)| **key != symb && value.related.contains(&symb))
+        .map(|(key, _)| *key)
+        .collect();
+    users.sort_by_key(|key| key.str());
+    users
+}
+
+// Compile again everything that depends on `symb`, directly or not,
+// so nothing keeps what it took from the old definition.
+fn recompile_dependents(map: &mut HashMap<Symb,DictValue>, symb: Symb) {
+    let mut done = vec![symb];
+    let mut queue = dependents(map, symb);
+    while let Some(key) = queue.pop() {
+        if done.contains(&key) {
+            continue;
+        }
+        done.push(key);
+        let text = map[&key].text.clone();
+        if let Some(new_value) = DictValue::new(key, text, map) {
+            set_link(key, new_value.linked);
+            map.insert(key, new_value);
+            println!("{:?} recompiled.", key);
+        }
+        queue.extend(dependents(map, key));
+    }
+}
+
 pub fn define(symb: Symb, input: String) -> Option<()> {
     let mut map = DICT_MAP.lock().unwrap();
     if !map.contains_key(&symb) {
-        if let Some(new_value) = DictValue::new(input) {
+        if let Some(new_value) = DictValue::new(symb,input,&map) {
+            set_link(symb, new_value.linked);
             map.insert(symb,new_value);
             println!("{:?} defined.",symb);
             Some(())
@@ -120,9 +353,11 @@ pub fn define(symb: Symb, input: String) -> Option<()> {
 pub fn update(symb: Symb, input: String) -> Option<()> {
     let mut map = DICT_MAP.lock().unwrap();
     if map.contains_key(&symb) {
-        if let Some(new_value) = DictValue::new(input) {
+        if let Some(new_value) = DictValue::new(symb,input,&map) {
+            set_link(symb, new_value.linked);
             map.insert(symb,new_value);
             println!("{:?} updated.",symb);
+            recompile_dependents(&mut map, symb);
             Some(())
         } else {
             println!("(:update) Can't parse term!");
@@ -137,7 +372,12 @@ pub fn update(symb: Symb, input: String) -> Option<()> {
 pub fn delete(symb: Symb) -> Option<()> {
     let mut map = DICT_MAP.lock().unwrap();
     if map.contains_key(&symb) {
+        let users = dependents(&map, symb);
+        if !users.is_empty() {
+            println!("warning: {:?} is still used by {:?}!", symb, users);
+        }
         map.remove(&symb);
+        set_link(symb, None);
         println!("{:?} deleted.", symb);
         Some(())
     } else {
@@ -153,18 +393,142 @@ pub fn show_dict() {
     }
 }
 
-pub fn dict_copy() {
+fn dict_value_save(writer: &mut Writer, key: Symb,
+                   value: &DictValue) -> Result<(),String> {
+    writer.record(image::RECORD_DICT);
+    writer.symb(key);
+    writer.string(&value.text);
+    writer.u32(value.related.len() as u32);
+    for symb in &value.related {
+        writer.symb(*symb);
+    }
+    writer.term(value.parsed)?;
+    writer.term(value.compiled)?;
+    // links are pointers, dict_insert makes them again
+    writer.u8(0);
+    Ok(())
+}
+
+pub fn dict_save(writer: &mut Writer) -> Result<usize,String> {
+    let map = DICT_MAP.lock().unwrap();
+    for (key, value) in &*map {
+        dict_value_save(writer, *key, value)?;
+    }
+    Ok(map.len())
+}
+
+// Compile definitions on their own, without touching the dictionary,
+// and save them in the order they came.
+pub fn compile_save(defs: Vec<(Symb,String)>,
+                    writer: &mut Writer) -> Result<usize,String> {
+    let mut map = HashMap::new();
+    let mut order = Vec::new();
+    for (key, input) in defs {
+        let value = DictValue::new(key, input, &map)
+            .ok_or_else(|| format!("Can't parse {:?}!", key))?;
+        if map.insert(key, value).is_none() {
+            order.push(key);
+        }
+    }
+    for key in &order {
+        dict_value_save(writer, *key, &map[key])?;
+    }
+    Ok(order.len())
+}
+
+pub fn dict_load(reader: &mut Reader) -> Result<(Symb,DictValue),String> {
+    let key = reader.symb()?;
+    let text = reader.string()?;
+    let mut related = Vec::new();
+    for _ in 0..reader.u32()? {
+        related.push(reader.symb()?);
+    }
+    let parsed = reader.term()?;
+    let compiled = reader.term()?;
+    let linked = if reader.u8()? != 0 { Some(reader.term()?) } else { None };
+    let (strict,params) = (Vec::new(),Vec::new());
+    Ok((key, DictValue { related, text, parsed, compiled, linked, strict, params }))
+}
+
+pub fn dict_insert(values: Vec<(Symb,DictValue)>) {
     let mut map = DICT_MAP.lock().unwrap();
-    for (_, value) in &mut *map {
-        dict_value_copy(value);
-        //map.insert(key,new_value);
+    for (key, mut value) in values {
+        value.strict = strict::analyze(Some(key), value.parsed, &|x| strictness(&map, x));
+        value.params = strict::params(value.parsed).0;
+        value.linked = Some(heap::perm(|| link(value.compiled)));
+        set_link(key, value.linked);
+        map.insert(key, value);
     }
 }
 
-pub fn dict_value_copy(dict: &mut DictValue) {
-    dict.parsed = term::term_copy(dict.parsed);
-    dict.compiled = term::term_copy(dict.parsed);
+pub fn dict_roots(roots: &mut dyn Roots) {
+    let mut map = DICT_MAP.lock().unwrap();
+    for (key, value) in &mut *map {
+        dict_value_roots(*key, value, roots);
+    }
+}
+
+pub fn dict_value_roots(key: Symb, dict: &mut DictValue,
+                        roots: &mut dyn Roots) {
+    dict.parsed = roots.root(dict.parsed,
+                    &format_args!("{:?}.parsed", key));
+    dict.compiled = roots.root(dict.compiled,
+                    &format_args!("{:?}.compiled", key));
     if let Some(linked) = dict.linked {
-        dict.linked = Some(term::term_copy(linked));
+        dict.linked = Some(roots.root(linked,
+                    &format_args!("{:?}.linked", key)));
+    }
+}
+#[test]
+pub fn link_test() {
+    let even = Symb::new("link_even");
+    let odd = Symb::new("link_odd");
+    define(even, "(\\n. if (= n 0) 1; link_odd (- n 1))".to_string());
+    define(odd, "(\\n. if (= n 0) 0; link_even (- n 1))".to_string());
+    assert_eq!(dependents(&DICT_MAP.lock().unwrap(), odd), vec![even]);
+    // the definitions point at each other, the lookup isn't needed
+    let linked = lookup(even).unwrap();
+    let mut stack = vec![linked];
+    let mut seen = HashSet::new();
+    while let Some(term) = stack.pop() {
+        if !seen.insert(term.index()) {
+            continue;
+        }
+        match *term {
+            Var(x) => { panic!("{:?} isn't linked", x); }
+            App(t1,t2) => { stack.push(t1); stack.push(t2); }
+            Link(_,t) => { stack.push(t); }
+            _ => {}
+        }
     }
-}
\ No newline at end of file
+    let (ret,_) = compile::reduce_steps(app!(linked,i!(7)));
+    assert_eq!(ret, "0");
+    update(odd, "(\\n. 5)".to_string());
+    let (ret,_) = compile::reduce_steps(app!(linked,i!(7)));
+    assert_eq!(ret, "5");
+}
+
+#[test]
+pub fn hash_cons_test() {
+    let x = Symb::new("cons_x");
+    let first = hash_cons(app!(var!(x),i!(1 << 40)));
+    let second = hash_cons(app!(var!(x),i!(1 << 40)));
+    assert_eq!(first.index(), second.index());
+    // built outside a definition, it was copied to a permanent page
+    assert!(heap::is_perm(first));
+    if let App(t1,t2) = *first {
+        assert!(heap::is_perm(t1) && heap::is_perm(t2));
+    }
+}
+
+#[test]
+pub fn deleted_definition_test() {
+    let inc = Symb::new("deleted_inc");
+    let twice = Symb::new("deleted_twice");
+    define(inc, "(\\n. + n 1)".to_string());
+    define(twice, "(\\n. deleted_inc (deleted_inc n))".to_string());
+    delete(inc);
+    let mut task = crate::eval::Task::new(app!(lookup(twice).unwrap(),i!(1)));
+    assert!(task.eval(100).is_none());
+    assert_eq!(task.take_error().as_deref(), Some("deleted_inc is not defined!"));
+}
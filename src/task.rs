@@ -1,76 +1,342 @@
 use crate::heap;
-use crate::eval::Task;
+use crate::eval::{Block, Task, TaskKind};
+use crate::spark;
+use crate::chan;
+use crate::image;
 
 use std::thread;
-use std::thread::JoinHandle;
-use std::time::Duration;
+use std::thread::{JoinHandle, Thread};
+use std::cell::Cell;
 use std::collections::VecDeque;
-use std::sync::Mutex;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::panic;
+use std::sync::{Condvar, Mutex, RwLock};
+use std::sync::mpsc::{channel, Sender, RecvTimeoutError};
+use std::time::Duration;
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum Priority {
+    Interactive,
+    Batch,
+}
+
+type CheckpointRequest = (usize, Sender<Result<Vec<u8>,String>>);
 
 lazy_static::lazy_static! {
-    static ref TASK_POOL: Mutex<VecDeque<Task>> =
+    // interactive tasks, checked by every worker before its own deque
+    static ref URGENT_POOL: Mutex<VecDeque<Task>> =
                             Mutex::new(VecDeque::new());
+    // batch tasks sent from outside the workers
+    static ref INJECT_POOL: Mutex<VecDeque<Task>> =
+                            Mutex::new(VecDeque::new());
+    // one deque per worker, the owner takes the newest task from the
+    // back and the others steal the oldest from the front
+    static ref LOCAL_POOL: RwLock<Vec<Mutex<VecDeque<Task>>>> =
+                            RwLock::new(Vec::new());
+    static ref IDLE_POOL: Mutex<Vec<Thread>> =
+                            Mutex::new(Vec::new());
     static ref HANDLE_POOL: Mutex<Vec<JoinHandle<()>>> =
                             Mutex::new(Vec::new());
+    // the printed result, a term could be moved by the next gc
+    static ref FOREGROUND: Mutex<Option<Sender<Result<String,String>>>> =
+                            Mutex::new(None);
+    // the task to save at the end of its timeslice, and who wants it
+    static ref CHECKPOINT: Mutex<Option<CheckpointRequest>> =
+                            Mutex::new(None);
+    // workers waiting for the gc, and how many gcs they have seen
+    static ref SAFEPOINT: (Mutex<(usize,usize)>, Condvar) =
+                            (Mutex::new((0,0)), Condvar::new());
 }
-static THREAD_MAX : usize = 8;
+static WORKER_MAX: AtomicUsize = AtomicUsize::new(8);
+static TIMESLICE: AtomicUsize = AtomicUsize::new(1024);
+// eval counts the steps of a timeslice in an i32
+pub const TIMESLICE_MAX: usize = i32::MAX as usize;
 static THREAD_COUNT: AtomicUsize = AtomicUsize::new(0);
+static INTERRUPT: AtomicBool = AtomicBool::new(false);
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    static WORKER_ID: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+pub fn worker_id() -> Option<usize> {
+    WORKER_ID.with(|id| id.get())
+}
+
+#[cfg(test)]
+pub fn become_worker(id: usize) {
+    WORKER_ID.with(|cell| cell.set(Some(id)));
+}
+
+// called from the SIGINT handler, so it may only touch atomics
+pub fn interrupt() {
+    INTERRUPT.store(true, Ordering::SeqCst);
+}
+
+// only takes effect before the first thread_init
+pub fn set_worker_max(n: usize) {
+    assert!(n > 0);
+    WORKER_MAX.store(n, Ordering::SeqCst);
+}
+
+pub fn set_timeslice(n: usize) {
+    assert!(n > 0 && n <= TIMESLICE_MAX);
+    TIMESLICE.store(n, Ordering::SeqCst);
+}
 
 pub fn thread_init() {
     assert_eq!(THREAD_COUNT.load(Ordering::SeqCst), 0);
     heap::set_singal_run();
+    {
+        let mut local = LOCAL_POOL.write().unwrap();
+        while local.len() < WORKER_MAX.load(Ordering::SeqCst) {
+            local.push(Mutex::new(VecDeque::new()));
+        }
+    }
     let mut handles = HANDLE_POOL.lock().unwrap();
-    for i in 0..THREAD_MAX {
-        handles.push(thread::spawn(thread_loop));
+    for i in 0..WORKER_MAX.load(Ordering::SeqCst) {
+        handles.push(thread::spawn(move || thread_loop(i)));
         THREAD_COUNT.fetch_add(1, Ordering::SeqCst);
         if cfg!(test) { println!("spawn thread {}", i); }
     }
 }
 
 pub fn thread_exit() {
-    let mut handles = HANDLE_POOL.lock().unwrap();
-    while let Some(handle) = handles.pop() {
-        handle.join().unwrap();
+    SHUTDOWN.store(true, Ordering::SeqCst);
+    heap::set_singal_stop();
+    {
+        let (lock, cvar) = &*SAFEPOINT;
+        let _state = lock.lock().unwrap();
+        cvar.notify_all();
+    }
+    loop {
+        let handle = HANDLE_POOL.lock().unwrap().pop();
+        if let Some(handle) = handle {
+            handle.join().unwrap();
+        } else {
+            break;
+        }
     }
 }
 
-pub fn fetch_task() -> Option<Task> {
-    let mut pool = TASK_POOL.lock().unwrap();
-    pool.pop_front()
+// urgent pool, own deque, inject pool, then steal from the others
+fn fetch_task(id: usize) -> Option<Task> {
+    if let Some(task) = URGENT_POOL.lock().unwrap().pop_front() {
+        return Some(task);
+    }
+    let local = LOCAL_POOL.read().unwrap();
+    if let Some(task) = local[id].lock().unwrap().pop_back() {
+        return Some(task);
+    }
+    if let Some(task) = INJECT_POOL.lock().unwrap().pop_front() {
+        return Some(task);
+    }
+    for i in 1..local.len() {
+        let victim = (id + i) % local.len();
+        if let Some(task) = local[victim].lock().unwrap().pop_front() {
+            return Some(task);
+        }
+    }
+    None
+}
+
+fn has_task() -> bool {
+    !URGENT_POOL.lock().unwrap().is_empty()
+        || !INJECT_POOL.lock().unwrap().is_empty()
+        || LOCAL_POOL.read().unwrap().iter()
+            .any(|deque| !deque.lock().unwrap().is_empty())
 }
 
 pub fn send_task(task: Task) {
-    let mut pool = TASK_POOL.lock().unwrap();
-    pool.push_back(task);
+    if task.priority() == Priority::Interactive {
+        URGENT_POOL.lock().unwrap().push_back(task);
+    } else if let Some(id) = WORKER_ID.with(|id| id.get()) {
+        LOCAL_POOL.read().unwrap()[id].lock().unwrap().push_back(task);
+    } else {
+        INJECT_POOL.lock().unwrap().push_back(task);
+    }
+    wake_one();
 }
 
 pub fn drain_task() -> Vec<Task> {
-    let mut pool = TASK_POOL.lock().unwrap();
-    let vec : Vec<Task> = pool.drain(..).collect();
+    let mut vec : Vec<Task> = Vec::new();
+    vec.extend(URGENT_POOL.lock().unwrap().drain(..));
+    vec.extend(INJECT_POOL.lock().unwrap().drain(..));
+    for deque in LOCAL_POOL.read().unwrap().iter() {
+        vec.extend(deque.lock().unwrap().drain(..));
+    }
     vec
 }
 
-fn thread_loop() {
-    while heap::singal_running() {
-        if let Some(mut task) = fetch_task() {
-            if let Some(ret) = task.eval(1024) {
-                println!("task end with: {:?} ", *ret);
-            } else {
-                send_task(task);
+// A task at the end of its timeslice goes behind the other tasks of
+// the worker, so they all get their turn.
+fn requeue(id: usize, task: Task) {
+    if task.priority() == Priority::Interactive {
+        URGENT_POOL.lock().unwrap().push_back(task);
+    } else {
+        LOCAL_POOL.read().unwrap()[id].lock().unwrap().push_front(task);
+    }
+    wake_one();
+}
+
+fn wake_one() {
+    if let Some(thread) = IDLE_POOL.lock().unwrap().pop() {
+        thread.unpark();
+    }
+}
+
+pub fn wake_all() {
+    for thread in IDLE_POOL.lock().unwrap().drain(..) {
+        thread.unpark();
+    }
+}
+
+fn park_idle() {
+    let me = thread::current();
+    IDLE_POOL.lock().unwrap().push(me.clone());
+    // a task may have been sent before we were registered
+    if !has_task() && heap::singal_running() {
+        thread::park();
+    }
+    // we may have been woken by something else than wake_one
+    IDLE_POOL.lock().unwrap().retain(|thread| thread.id() != me.id());
+}
+
+// Run a task on the worker threads and block until it finishes.
+// Ctrl-C aborts it between two timeslices.
+pub fn run_foreground(mut task: Task) -> Result<String,String> {
+    let (sender, receiver) = channel();
+    *FOREGROUND.lock().unwrap() = Some(sender);
+    INTERRUPT.store(false, Ordering::SeqCst);
+    task.set_foreground();
+    heap::hand_over(|| send_task(task));
+    loop {
+        match receiver.recv_timeout(Duration::from_millis(50)) {
+            Ok(result) => { return result; }
+            Err(RecvTimeoutError::Timeout) => {
+                // a parked task is never seen by the workers
+                if INTERRUPT.load(Ordering::SeqCst) {
+                    let parked = spark::drop_foreground()
+                        .or_else(chan::drop_foreground);
+                    if let Some(task) = parked {
+                        INTERRUPT.store(false, Ordering::SeqCst);
+                        fail_task(task, "Interrupted!".to_string());
+                    }
+                }
             }
-        } else {
-            thread::sleep(Duration::from_millis(10));
+            Err(RecvTimeoutError::Disconnected) => { unreachable!(); }
         }
     }
+}
+
+fn reply_foreground(result: Result<String,String>) {
+    if let Some(sender) = FOREGROUND.lock().unwrap().take() {
+        sender.send(result).unwrap();
+    }
+}
+
+// Save a task the next time a worker stops running it. The worker
+// writes the image itself, so no gc can move the terms meanwhile.
+pub fn checkpoint(id: usize) -> Result<Vec<u8>,String> {
+    let (sender, receiver) = channel();
+    *CHECKPOINT.lock().unwrap() = Some((id, sender));
+    let result = receiver.recv_timeout(Duration::from_secs(5));
+    CHECKPOINT.lock().unwrap().take();
+    result.unwrap_or_else(|_| Err(format!("Task #{} isn't running!", id)))
+}
+
+fn serve_checkpoint(task: &Task) {
+    let mut request = CHECKPOINT.lock().unwrap();
+    if matches!(*request, Some((id,_)) if id == task.id()) {
+        let (_, sender) = request.take().unwrap();
+        let _ = sender.send(image::save_task(task));
+    }
+}
+
+// Drop a task that can't go on, telling whoever waits for it. The
+// sparks it was evaluating are left for someone else.
+pub fn fail_task(task: Task, msg: String) {
+    spark::release(task.id());
+    if task.is_foreground() {
+        reply_foreground(Err(msg));
+    } else {
+        println!("task failed with: {}", msg);
+    }
+}
+
+// Called by a worker once the heap asks to stop. The last one to
+// arrive runs the gc while the others wait, then they all go on.
+fn safepoint() {
     heap::dump_page();
-    let old_count = THREAD_COUNT.fetch_sub(1, Ordering::SeqCst);
-    //thread_debug_log();
-    if old_count == 1 {
+    let (lock, cvar) = &*SAFEPOINT;
+    let mut state = lock.lock().unwrap();
+    state.0 += 1;
+    if state.0 == THREAD_COUNT.load(Ordering::SeqCst) {
         // Oh! you are the chosen one!
         // Do the garbage collection please!
-        heap::run_gc();
-        thread_init();
+        if !SHUTDOWN.load(Ordering::SeqCst) {
+            heap::run_gc();
+        }
+        state.0 = 0;
+        state.1 += 1;
+        heap::set_singal_run();
+        cvar.notify_all();
+    } else {
+        let epoch = state.1;
+        while state.1 == epoch && !SHUTDOWN.load(Ordering::SeqCst) {
+            state = cvar.wait(state).unwrap();
+        }
+    }
+}
+
+fn thread_loop(id: usize) {
+    WORKER_ID.with(|cell| cell.set(Some(id)));
+    while !SHUTDOWN.load(Ordering::SeqCst) {
+        if !heap::singal_running() {
+            safepoint();
+        } else if let Some(mut task) = fetch_task(id) {
+            let timeslice = i32::try_from(TIMESLICE.load(Ordering::Relaxed))
+                .unwrap_or(i32::MAX);
+            let res = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+                task.eval(timeslice)
+            }));
+            match res {
+                Ok(Some(ret)) => {
+                    match task.kind() {
+                        TaskKind::Foreground => {
+                            reply_foreground(Ok(format!("{:?}", *ret)));
+                        }
+                        TaskKind::Background => {
+                            println!("task #{} end with: {:?} ",
+                                task.id(), *ret);
+                        }
+                        TaskKind::Spark => {}
+                    }
+                }
+                Ok(None) => {
+                    serve_checkpoint(&task);
+                    if let Some(msg) = task.take_error() {
+                        fail_task(task, msg);
+                    } else if task.is_foreground()
+                        && INTERRUPT.swap(false, Ordering::SeqCst) {
+                        fail_task(task, "Interrupted!".to_string());
+                    } else if let Some(block) = task.take_blocked() {
+                        match block {
+                            Block::Spark(id) => { spark::wait(id, task); }
+                            Block::Chan(id) => { chan::wait(id, task); }
+                        }
+                    } else {
+                        requeue(id, task);
+                    }
+                }
+                Err(_) => {
+                    fail_task(task, "Task aborted!".to_string());
+                }
+            }
+        } else {
+            park_idle();
+        }
     }
     heap::dump_page();
     // Ok, you die now.
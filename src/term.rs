@@ -5,6 +5,8 @@ use std::fmt::Debug;
 
 use crate::term::Term::*;
 use crate::symbol::Symb;
+use crate::heap;
+use crate::lift;
 
 #[derive(Clone,Copy,PartialEq)]
 pub enum Term {
@@ -20,35 +22,84 @@ pub enum Term {
     AddI,SubI,MulI,DivI,
     GrtI,LssI,EqlI,
     Not,And,Or,Ifte,
+    Seq,Par,
+    Spark(usize),Fulfil(usize),
+    NewChan,SendChan,RecvChan,Chan(usize),
+    // a lifted supercombinator and the arguments in its body
+    Super(usize),Arg(usize),
+    // where the code of a supercombinator goes on once a value is back,
+    // the code itself is kept by the task
+    Code(u32),
+    // a linked reference to a definition, shared by everyone using it
+    Link(Symb,TermRef),
+    // left behind in from-space by the gc
+    Moved(TermRef),
     //List(TermRef,TermRef),
     //EndOfList,
-    Array(usize,*mut TermRef),
+    Array(u32,TermRef),
     Alloc,Free,Load,Save,
 }
 
+// A handle to a term, the upper bits pick a page slot in the heap
+// and the lower bits an offset in that page. Slot 0 is reserved for
+// the constant combinators. Debug builds also keep the generation
+// of the slot to catch handles into freed pages.
 #[derive(Eq)]
-pub struct TermRef(*mut Term);
+pub struct TermRef {
+    index: u32,
+    #[cfg(debug_assertions)]
+    gen: u32,
+}
 
-unsafe impl Send for TermRef {}
-unsafe impl Sync for TermRef {}
 impl TermRef {
-    pub fn new(ptr: *mut Term) -> TermRef {
-        TermRef(ptr)
-    } 
-    unsafe fn set(&self, x: Term) {
-        *self.0 = x;
+    pub fn new(slot: usize, offset: usize) -> TermRef {
+        debug_assert!(slot > 0 && slot < heap::SLOT_MAX);
+        debug_assert!(offset < 1 << heap::OFFSET_BITS);
+        TermRef {
+            index: (slot << heap::OFFSET_BITS | offset) as u32,
+            #[cfg(debug_assertions)]
+            gen: heap::slot_gen(slot),
+        }
+    }
+    const fn constant(offset: u32) -> TermRef {
+        TermRef {
+            index: offset,
+            #[cfg(debug_assertions)]
+            gen: 0,
+        }
+    }
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+    pub fn slot(&self) -> usize {
+        (self.index >> heap::OFFSET_BITS) as usize
+    }
+    pub fn offset(&self) -> usize {
+        (self.index & ((1 << heap::OFFSET_BITS) - 1)) as usize
+    }
+    #[cfg(debug_assertions)]
+    pub fn gen(&self) -> u32 {
+        self.gen
+    }
+    pub unsafe fn set(&self, x: Term) {
+        assert!(self.slot() != 0, "constants can't be updated!");
+        *heap::term_ptr(self) = x;
     }
 }
 impl Deref for TermRef {
     type Target = Term;
     fn deref(&self) -> &Term {
-        unsafe { &*self.0 }
+        if self.slot() == 0 {
+            &CONSTS[self.offset()]
+        } else {
+            unsafe { &*heap::term_ptr(self) }
+        }
     }
 }
 impl Copy for TermRef {}
 impl Clone for TermRef {
     fn clone(&self) -> TermRef {
-        TermRef(self.0)
+        *self
     }
 }
 impl Debug for TermRef {
@@ -59,39 +110,82 @@ impl Debug for TermRef {
 }
 impl PartialEq for TermRef {
     fn eq(&self, other: &Self) -> bool {
-           self.0 == other.0
+           self.index == other.index
         || self.deref() == other.deref()
     }
 }
 
-macro_rules! const_term {
-    ($var:ident, $term:expr) => {
-        pub static $var : TermRef = TermRef(&$term as *const Term as *mut Term);
+macro_rules! const_terms {
+    ($($var:ident = $term:expr),*) => {
+        const NAMED: &[Term] = &[$($term),*];
+        const_terms!(@index 0, $($var),*);
+    };
+    (@index $i:expr, $var:ident $(, $rest:ident)*) => {
+        pub static $var : TermRef = TermRef::constant($i);
+        const_terms!(@index $i + 1, $($rest),*);
     };
+    (@index $i:expr,) => {};
 }
-const_term!(C_I,I);
-const_term!(C_K,K);
-const_term!(C_S,S);
-const_term!(C_B,B);
-const_term!(C_C,C);
-const_term!(C_SP,Sp);
-const_term!(C_BS,Bs);
-const_term!(C_CP,Cp);
-const_term!(C_E1,E1);
-const_term!(C_E2,E2);
-const_term!(C_E3,E3);
-const_term!(C_E4,E4);
-const_term!(C_ADDI,AddI);
-const_term!(C_SUBI,SubI);
-const_term!(C_MULI,MulI);
-const_term!(C_DIVI,DivI);
-const_term!(C_GRTI,GrtI);
-const_term!(C_LSSI,LssI);
-const_term!(C_EQLI,EqlI);
-const_term!(C_NOT,Not);
-const_term!(C_AND,And);
-const_term!(C_OR,Or);
-const_term!(C_IFTE,Ifte);
+const_terms! {
+    C_I = I,
+    C_K = K,
+    C_S = S,
+    C_B = B,
+    C_C = C,
+    C_SP = Sp,
+    C_BS = Bs,
+    C_CP = Cp,
+    C_E1 = E1,
+    C_E2 = E2,
+    C_E3 = E3,
+    C_E4 = E4,
+    C_ADDI = AddI,
+    C_SUBI = SubI,
+    C_MULI = MulI,
+    C_DIVI = DivI,
+    C_GRTI = GrtI,
+    C_LSSI = LssI,
+    C_EQLI = EqlI,
+    C_NOT = Not,
+    C_AND = And,
+    C_OR = Or,
+    C_IFTE = Ifte,
+    C_SEQ = Seq,
+    C_PAR = Par,
+    C_NEWCHAN = NewChan,
+    C_SEND = SendChan,
+    C_RECV = RecvChan,
+    C_TRUE = DBool(true),
+    C_FALSE = DBool(false)
+}
+
+// Small integers and ascii chars are preallocated after the named
+// constants, so arithmetic on them doesn't touch the heap.
+pub const SMALL_INT_MIN: i64 = -128;
+pub const SMALL_INT_MAX: i64 = 1023;
+const ASCII_COUNT: usize = 128;
+const INT_BASE: usize = NAMED.len();
+const CHAR_BASE: usize =
+    INT_BASE + (SMALL_INT_MAX - SMALL_INT_MIN + 1) as usize;
+const CONST_COUNT: usize = CHAR_BASE + ASCII_COUNT;
+
+static CONSTS: [Term; CONST_COUNT] = {
+    let mut table = [I; CONST_COUNT];
+    let mut i = 0;
+    while i < INT_BASE {
+        table[i] = NAMED[i];
+        i += 1;
+    }
+    while i < CHAR_BASE {
+        table[i] = DInt(SMALL_INT_MIN + (i - INT_BASE) as i64);
+        i += 1;
+    }
+    while i < CONST_COUNT {
+        table[i] = DChar((i - CHAR_BASE) as u8 as char);
+        i += 1;
+    }
+    table
+};
 
 #[macro_export]
 macro_rules! alloc {
@@ -115,24 +209,19 @@ macro_rules! eager {
 #[macro_export]
 macro_rules! b {
     ($v:expr) => {
-        alloc!(DBool($v))
+        if $v { $crate::term::C_TRUE } else { $crate::term::C_FALSE }
     };
 }
 #[macro_export]
 macro_rules! c {
     ($v:expr) => {
-        alloc!(DChar($v))
+        $crate::term::char_term($v)
     };
 }
 #[macro_export]
 macro_rules! i {
     ($v:expr) => {
-        alloc!(DInt($v))
-    };
-}
-macro_rules! r {
-    ($v:expr) => {
-        alloc!(DReal($v))
+        $crate::term::int_term($v)
     };
 }
 
@@ -163,6 +252,49 @@ macro_rules! var {
     };
 }
 
+pub fn int_term(n: i64) -> TermRef {
+    if (SMALL_INT_MIN..=SMALL_INT_MAX).contains(&n) {
+        TermRef::constant((INT_BASE as i64 + n - SMALL_INT_MIN) as u32)
+    } else {
+        alloc!(DInt(n))
+    }
+}
+
+pub fn char_term(c: char) -> TermRef {
+    if (c as usize) < ASCII_COUNT {
+        TermRef::constant((CHAR_BASE + c as usize) as u32)
+    } else {
+        alloc!(DChar(c))
+    }
+}
+
+pub fn const_count() -> usize {
+    CONSTS.len()
+}
+
+pub fn const_ref(offset: usize) -> Option<TermRef> {
+    if offset < CONSTS.len() {
+        Some(TermRef::constant(offset as u32))
+    } else {
+        None
+    }
+}
+
+// The constant a leaf is equal to, if there is one.
+pub fn find_const(term: &Term) -> Option<TermRef> {
+    match term {
+        DInt(n) if (SMALL_INT_MIN..=SMALL_INT_MAX).contains(n) => {
+            Some(int_term(*n))
+        }
+        DChar(c) if (*c as usize) < ASCII_COUNT => Some(char_term(*c)),
+        App(_,_) | Lam(_,_) | Var(_) | Link(_,_) => None,
+        _ => {
+            let offset = NAMED.iter().position(|c| c == term)?;
+            Some(TermRef::constant(offset as u32))
+        }
+    }
+}
+
 
 
 impl Term {
@@ -196,7 +328,7 @@ impl Debug for Term {
                 write!(f,". ")?;
                 with.app_list_fmt(f)?;
             }
-            Var(x) => { write!(f,"{:?}",x)?; }
+            Var(x) | Link(x,_) => { write!(f,"{:?}",x)?; }
             DBool(x) => { write!(f,"{}",x)?; }
             DChar(x) => { write!(f,"{}",x)?; }
             DInt(x) => { write!(f,"{}",x)?; }
@@ -225,7 +357,19 @@ impl Debug for Term {
             And => { write!(f,"And")?; }
             Or => { write!(f,"Or")?; }
             Ifte => { write!(f,"Ifte")?; }
-            Array(n,ptr) => { write!(f,"Array{}:{:p}",n,ptr)?; }
+            Seq => { write!(f,"Seq")?; }
+            Par => { write!(f,"Par")?; }
+            Spark(id) => { write!(f,"Spark#{}",id)?; }
+            Fulfil(id) => { write!(f,"Fulfil#{}",id)?; }
+            NewChan => { write!(f,"NewChan")?; }
+            SendChan => { write!(f,"SendChan")?; }
+            RecvChan => { write!(f,"RecvChan")?; }
+            Chan(id) => { write!(f,"Chan#{}",id)?; }
+            Super(id) => { write!(f,"${}",lift::name(*id))?; }
+            Arg(i) => { write!(f,"Arg#{}",i)?; }
+            Code(pc) => { write!(f,"Code@{}",pc)?; }
+            Moved(t) => { write!(f,"Moved:#{}",t.index)?; }
+            Array(n,t) => { write!(f,"Array{}:#{}",n,t.index)?; }
             Alloc => { write!(f,"Alloc")?; }
             Free => { write!(f,"Free")?; }
             Load => { write!(f,"Load")?; }
@@ -235,30 +379,17 @@ impl Debug for Term {
     }
 }
 
-pub fn term_copy(term: TermRef) -> TermRef {
-    //println!("term:{:?}",term);
-    match *term {
-        App(t1,t2) => {
-            let new_t1 = term_copy(t1);
-            let new_t2 = term_copy(t2);
-            app!(new_t1,new_t2)
-        }
-        Lam(x,t) => {
-            let new_t = term_copy(t);
-            lam!(x,new_t)
-        }
-        Var(x) => {
-            var!(x)
-        }
-        DChar(x) => { c!(x) }
-        DBool(x) => { b!(x) }
-        DInt(x) => { i!(x) }
-        DReal(x) => { r!(x) }
-        E(n) => {
-            eager!(n)
-        }
-        _ => {
-            term
-        }
+#[test]
+pub fn small_value_test() {
+    for n in [SMALL_INT_MIN, 0, 1, SMALL_INT_MAX] {
+        let term = int_term(n);
+        assert_eq!(term.slot(), 0);
+        assert_eq!(*term, DInt(n));
     }
-}
\ No newline at end of file
+    assert_eq!(*char_term('a'), DChar('a'));
+    assert_eq!(char_term('a').index(), char_term('a').index());
+    let big = int_term(SMALL_INT_MAX + 1);
+    assert_ne!(big.slot(), 0);
+    assert_eq!(*big, DInt(SMALL_INT_MAX + 1));
+    assert_eq!(*b!(true), DBool(true));
+}
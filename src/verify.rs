@@ -0,0 +1,176 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::heap;
+use crate::heap::Roots;
+use crate::term::TermRef;
+use crate::term::Term::*;
+use crate::eval;
+use crate::eval::Task;
+use crate::symbol;
+use crate::spark;
+use crate::chan;
+use crate::lift;
+
+// The verifier walks everything reachable from the roots and stops
+// at the first broken term, so a bad copy in the gc shows up right
+// after the collection instead of as a crash much later.
+static VERIFY: AtomicBool = AtomicBool::new(cfg!(feature = "verify"));
+static VERIFY_STEPS: AtomicUsize = AtomicUsize::new(0);
+
+pub fn enabled() -> bool {
+    VERIFY.load(Ordering::Relaxed)
+}
+
+pub fn set_verify(flag: bool) {
+    VERIFY.store(flag, Ordering::Relaxed);
+}
+
+// 0 only verifies around the gc
+pub fn set_verify_steps(n: usize) {
+    VERIFY_STEPS.store(n, Ordering::Relaxed);
+}
+
+pub fn verify_steps() -> usize {
+    VERIFY_STEPS.load(Ordering::Relaxed)
+}
+
+pub fn step_interval() -> Option<usize> {
+    let n = VERIFY_STEPS.load(Ordering::Relaxed);
+    if enabled() && n > 0 { Some(n) } else { None }
+}
+
+pub struct Verifier<'a> {
+    // slots the gc just moved out of
+    dumped: Option<&'a [bool]>,
+    visited: HashSet<u32>,
+    sparks: HashSet<usize>,
+    chans: HashSet<usize>,
+    // the table is locked while its roots are walked
+    supers: HashSet<usize>,
+    error: Option<String>,
+}
+
+impl Roots for Verifier<'_> {
+    fn root(&mut self, term: TermRef, name: &dyn fmt::Display) -> TermRef {
+        if self.error.is_none() {
+            self.walk(term, name.to_string());
+        }
+        term
+    }
+}
+
+impl<'a> Verifier<'a> {
+    pub fn new(dumped: Option<&'a [bool]>) -> Verifier<'a> {
+        Verifier {
+            dumped,
+            visited: HashSet::new(),
+            sparks: spark::ids(),
+            chans: chan::ids(),
+            supers: lift::ids(),
+            error: None,
+        }
+    }
+    fn check_ref(&self, term: TermRef) -> Result<(),String> {
+        heap::check_ref(term)?;
+        if let Some(dumped) = self.dumped {
+            if dumped[term.slot()] {
+                return Err(format!("#{} points to a dumped page",
+                    term.index()));
+            }
+        }
+        Ok(())
+    }
+    fn check_term(&self, term: TermRef) -> Result<(),String> {
+        match *term {
+            Moved(_) => {
+                Err("forwarding pointer outside the gc".to_string())
+            }
+            E(0) => {
+                Err("eager combinator with no argument".to_string())
+            }
+            Var(x) | Lam(x,_) | Link(x,_) if !x.exists() => {
+                Err(format!("#{} binds an unknown symbol", term.index()))
+            }
+            Spark(id) | Fulfil(id) if !self.sparks.contains(&id) => {
+                Err(format!("{:?} is not a live spark", *term))
+            }
+            Chan(id) if !self.chans.contains(&id) => {
+                Err(format!("{:?} is not a live channel", *term))
+            }
+            Super(id) if !self.supers.contains(&id) => {
+                Err(format!("Super#{} is not a supercombinator", id))
+            }
+            _ => Ok(())
+        }
+    }
+    // depth first, `path` holds the edges from the root to `term`
+    fn walk(&mut self, root: TermRef, name: String) {
+        let mut path: Vec<String> = Vec::new();
+        let mut stack = vec![(root, name, 0)];
+        while let Some((term, edge, depth)) = stack.pop() {
+            path.truncate(depth);
+            path.push(edge);
+            if let Err(msg) = self.check_ref(term) {
+                self.error = Some(format!("{} at {}", msg, path.join(".")));
+                return;
+            }
+            if !self.visited.insert(term.index()) {
+                continue;
+            }
+            if let Err(msg) = self.check_term(term) {
+                self.error = Some(format!("{} at {}", msg, path.join(".")));
+                return;
+            }
+            match *term {
+                App(t1,t2) => {
+                    stack.push((t2, "arg".to_string(), depth + 1));
+                    stack.push((t1, "fun".to_string(), depth + 1));
+                }
+                Lam(_,t) => {
+                    stack.push((t, "body".to_string(), depth + 1));
+                }
+                Array(_,t) => {
+                    stack.push((t, "array".to_string(), depth + 1));
+                }
+                Link(x,t) => {
+                    stack.push((t, format!("{:?}", x), depth + 1));
+                }
+                _ => {}
+            }
+        }
+    }
+    pub fn report(&self, when: &str) -> bool {
+        if let Some(msg) = &self.error {
+            println!("[verify] {}: {}", when, msg);
+            false
+        } else {
+            true
+        }
+    }
+}
+
+// Called with the world stopped, `tasks` are the ones the gc holds.
+pub fn verify_heap(when: &str, tasks: &mut [Task],
+                   dumped: Option<&[bool]>) -> bool {
+    let mut verifier = Verifier::new(dumped);
+    for task in tasks.iter_mut() {
+        eval::task_roots(task, &mut verifier);
+    }
+    symbol::dict_roots(&mut verifier);
+    symbol::cons_roots(&mut verifier);
+    lift::super_roots(&mut verifier);
+    spark::spark_roots(&mut verifier);
+    chan::chan_roots(&mut verifier);
+    for node in heap::remembered() {
+        verifier.root(node, &"remembered");
+    }
+    verifier.report(when)
+}
+
+pub fn verify_task(task: &mut Task) -> bool {
+    let mut verifier = Verifier::new(None);
+    eval::task_roots(task, &mut verifier);
+    verifier.report("during eval")
+}